@@ -32,7 +32,7 @@ pub fn from_csv(csv_file: &str) -> Option<AppAwsCredentials> {
     let mut csv_rdr = csv::Reader::from_reader(csv_file.unwrap());
     for row in csv_rdr.deserialize::<AwsCsvEntry>() {
         if let Ok(record) = row {
-            println!("Record: {:?}", record);
+            debug!("Using the credentials of {}", record.user_name);
             return Some(AppAwsCredentials {
                 access_key: record.access_key,
                 secret_access_key: record.secret_access_key,
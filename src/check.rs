@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+use crate::config::{AppConfig, RecordSet};
+use crate::dns;
+use crate::ip_address;
+use crate::route53_client::{self, Route53Client};
+
+/// Monitoring plugin states; the discriminants are the exit codes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum State {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl State {
+    fn name(self) -> &'static str {
+        match self {
+            State::Ok => "OK",
+            State::Warning => "WARNING",
+            State::Critical => "CRITICAL",
+            State::Unknown => "UNKNOWN",
+        }
+    }
+
+    /// Severity used to pick the overall state: a critical problem
+    /// outweighs a warning, which outweighs an unknown
+    fn severity(self) -> u8 {
+        match self {
+            State::Ok => 0,
+            State::Unknown => 1,
+            State::Warning => 2,
+            State::Critical => 3,
+        }
+    }
+}
+
+struct RecordCheck {
+    state: State,
+    message: String,
+}
+
+fn join(addresses: &[IpAddr]) -> String {
+    if addresses.is_empty() {
+        return String::from("nothing");
+    }
+
+    let addresses: Vec<String> =
+        addresses.iter().map(|ip| ip.to_string()).collect();
+    addresses.join(", ")
+}
+
+/// Whether the answers of the authoritative name servers can be compared
+/// with the detected address: with a routing policy they answer with the
+/// address of whichever record set they pick, so only Route53 is checked
+fn checks_name_servers(record_set: &RecordSet<'_>) -> bool {
+    record_set.routing.is_none()
+}
+
+/// Resolves the addresses of the name servers
+async fn resolve_name_servers(names: &[String]) -> Vec<(String, SocketAddr)> {
+    let mut name_servers = Vec::new();
+    for name in names.iter() {
+        let addr = tokio::net::lookup_host((name.as_str(), 53))
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next());
+        if let Some(addr) = addr {
+            name_servers.push((name.clone(), addr));
+        }
+    }
+    name_servers
+}
+
+async fn check_record_set(
+    client: &Route53Client,
+    name_servers: &[(String, SocketAddr)],
+    record_set: &RecordSet<'_>,
+) -> RecordCheck {
+    let is_v6 = record_set.is_v6;
+    let (record_type, qtype) = if is_v6 {
+        ("AAAA", dns::TYPE_AAAA)
+    } else {
+        ("A", dns::TYPE_A)
+    };
+    let name = format!("{} ({})", record_set.name, record_type);
+
+    let detected = match ip_address::record_set_address(record_set).await {
+        Ok((ip, _)) => ip,
+        Err(err) => {
+            return RecordCheck {
+                state: State::Unknown,
+                message: format!("{}: {}", name, err),
+            }
+        }
+    };
+
+    // What Route53 has
+    let published = match client
+        .get_record_set(
+            record_set.zone_id,
+            record_set.name,
+            record_type,
+            record_set.routing.map(|routing| routing.set_identifier),
+        )
+        .await
+    {
+        Ok(Some(entry)) => route53_client::published_addresses(&entry),
+        Ok(None) => Vec::new(),
+        Err(err) => {
+            return RecordCheck {
+                state: State::Unknown,
+                message: format!("{}: Route53 error: {}", name, err),
+            }
+        }
+    };
+    if !published.contains(&detected) {
+        return RecordCheck {
+            state: State::Critical,
+            message: format!(
+                "{}: Route53 has {}, detected {}",
+                name,
+                join(&published),
+                detected
+            ),
+        };
+    }
+
+    // What the authoritative name servers answer
+    if !checks_name_servers(record_set) {
+        return RecordCheck {
+            state: State::Ok,
+            message: format!("{}: {} is published", name, detected),
+        };
+    }
+    for (ns_name, ns_addr) in name_servers.iter() {
+        match dns::query(*ns_addr, record_set.name, qtype).await {
+            Ok(answer) if answer.contains(&detected) => {}
+            Ok(answer) => {
+                return RecordCheck {
+                    state: State::Warning,
+                    message: format!(
+                        "{}: {} answers {}, detected {}",
+                        name,
+                        ns_name,
+                        join(&answer),
+                        detected
+                    ),
+                }
+            }
+            Err(err) => {
+                return RecordCheck {
+                    state: State::Warning,
+                    message: format!("{}: {}: {}", name, ns_name, err),
+                }
+            }
+        }
+    }
+
+    RecordCheck {
+        state: State::Ok,
+        message: format!("{}: {} is published", name, detected),
+    }
+}
+
+/// Compares the detected addresses with Route53 and the authoritative name
+/// servers, without changing anything. Prints the plugin output and returns
+/// the state.
+pub async fn check(config: &AppConfig, client: &Route53Client) -> State {
+    let started_at = Instant::now();
+
+    // Name servers of each hosted zone
+    let mut zones = HashMap::new();
+    for record_set in config.record_sets() {
+        if zones.contains_key(record_set.zone_id) {
+            continue;
+        }
+
+        match client.get_name_servers(record_set.zone_id).await {
+            Ok(names) => {
+                let name_servers = resolve_name_servers(&names).await;
+                zones.insert(record_set.zone_id, name_servers);
+            }
+            Err(err) => {
+                println!("DDNS UNKNOWN - Route53 error: {}", err);
+                return State::Unknown;
+            }
+        }
+    }
+
+    let mut checks = Vec::new();
+    for record_set in config.record_sets() {
+        let is_v6_unavailable = record_set.is_v6
+            && record_set.interface.is_none()
+            && !ip_address::is_ipv6_available();
+        if is_v6_unavailable {
+            continue;
+        }
+
+        let name_servers = &zones[record_set.zone_id];
+        checks.push(check_record_set(client, name_servers, &record_set).await);
+    }
+
+    let state = checks
+        .iter()
+        .map(|check| check.state)
+        .max_by_key(|state| state.severity())
+        .unwrap_or(State::Unknown);
+
+    let summary = if checks.is_empty() {
+        String::from("no record sets to check")
+    } else if state == State::Ok {
+        format!("{} record set(s) up to date", checks.len())
+    } else {
+        let problems: Vec<&str> = checks
+            .iter()
+            .filter(|check| check.state != State::Ok)
+            .map(|check| check.message.as_str())
+            .collect();
+        problems.join("; ")
+    };
+
+    let count = |state: State| {
+        checks.iter().filter(|check| check.state == state).count()
+    };
+    println!(
+        "DDNS {} - {} | records={} ok={} warning={} critical={} unknown={} \
+         time={:.3}s",
+        state.name(),
+        summary,
+        checks.len(),
+        count(State::Ok),
+        count(State::Warning),
+        count(State::Critical),
+        count(State::Unknown),
+        started_at.elapsed().as_secs_f64()
+    );
+    for check in checks.iter() {
+        println!("{}", check.message);
+    }
+
+    state
+}
+
+#[cfg(test)]
+mod check_tests {
+    use super::*;
+    use crate::config::{ConflictPolicy, DriftPolicy, Routing};
+
+    #[test]
+    fn name_servers_skipped_with_routing() {
+        let mut record_set = RecordSet {
+            name: "home.example.com.",
+            zone_id: "Z1",
+            is_v6: false,
+            providers: Vec::new(),
+            interface: None,
+            on_drift: DriftPolicy::Ignore,
+            on_conflict: ConflictPolicy::Refuse,
+            routing: None,
+        };
+        assert!(checks_name_servers(&record_set));
+
+        record_set.routing = Some(Routing {
+            set_identifier: "home",
+            weight: Some(10),
+            failover: None,
+            multivalue: false,
+            health_check_id: None,
+        });
+        assert!(!checks_name_servers(&record_set));
+    }
+}
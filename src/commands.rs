@@ -0,0 +1,289 @@
+use crate::config::{self, AppConfig, RecordSet};
+use crate::ip_address;
+use crate::prefix;
+use crate::report::{self, OutputFormat, RecordReport};
+use crate::route53_client::{self, Route53Client};
+use crate::state::{self, State};
+
+/// Checks the configuration offline, printing the merged result and failing
+/// if there are problems
+pub fn validate(
+    config_file: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = AppConfig::load_unchecked(config_file)?;
+
+    println!("# Merged from:");
+    for layer in config::layers(config_file).iter() {
+        println!("#   {}", layer.display());
+    }
+    for (name, _) in std::env::vars() {
+        if name.starts_with("RUST_AWS_DDNS_") {
+            println!("#   ${}", name);
+        }
+    }
+
+    let problems = config.validate();
+    println!("{}", config.to_masked_yaml()?);
+
+    if problems.is_empty() {
+        println!("The configuration is valid.");
+        return Ok(());
+    }
+
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    Err(format!("{} problem(s) found", problems.len()).into())
+}
+
+/// Rewrites the configuration file on the current schema, keeping a backup
+/// of the original
+pub fn migrate_config(
+    config_file: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let f = std::fs::File::open(config_file)?;
+    let mut value: serde_yaml::Value = serde_yaml::from_reader(f)?;
+
+    let version = config::migrate(&mut value)?;
+    if version == config::CONFIG_VERSION {
+        println!("{} is already on version {}.", config_file, version);
+        return Ok(());
+    }
+
+    let backup = format!("{}.bak", config_file);
+    std::fs::copy(config_file, &backup)?;
+    config::write_private(config_file, &serde_yaml::to_string(&value)?)?;
+
+    println!(
+        "Migrated {} from version {} to {} (the original is on {}).",
+        config_file,
+        version,
+        config::CONFIG_VERSION,
+        backup
+    );
+    Ok(())
+}
+
+/// Prints the hosted zones of the account
+pub async fn list_zones(
+    client: &Route53Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let hosted_zones = client
+        .list_hosted_zones()
+        .await
+        .ok_or("Error fetching Route53 hosted zones")?;
+
+    for (zone_id, zone_name) in hosted_zones.iter() {
+        println!("{}\t{}", zone_id, zone_name);
+    }
+
+    Ok(())
+}
+
+/// Prints the record sets of a hosted zone, specified by its id or name
+pub async fn list_records(
+    client: &Route53Client,
+    zone: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let hosted_zones = client
+        .list_hosted_zones()
+        .await
+        .ok_or("Error fetching Route53 hosted zones")?;
+
+    let zone_id = route53_client::find_hosted_zone(&hosted_zones, zone)
+        .map(|(zone_id, _)| zone_id.as_str())
+        .ok_or_else(|| format!("Hosted zone {} not found", zone))?;
+
+    for record_set in client.list_record_sets(zone_id).await?.iter() {
+        let values: Vec<String> = match &record_set.resource_records {
+            Some(records) => {
+                records.iter().map(|record| record.value.clone()).collect()
+            }
+            None => match &record_set.alias_target {
+                Some(alias) => vec![format!("ALIAS {}", alias.dns_name)],
+                None => Vec::new(),
+            },
+        };
+        let ttl = record_set
+            .ttl
+            .map_or(String::from("-"), |ttl| ttl.to_string());
+
+        println!(
+            "{}\t{}\t{}\t{}",
+            record_set.name,
+            record_set.type_,
+            ttl,
+            values.join(",")
+        );
+    }
+
+    Ok(())
+}
+
+/// Adds the address Route53 has for the record set to the report, the
+/// detected one if it is among them. Returns false if Route53 couldn't be
+/// read, with the error on the report.
+async fn add_published(
+    client: &Route53Client,
+    record_set: &RecordSet<'_>,
+    report: &mut RecordReport,
+) -> bool {
+    let record_type = if record_set.is_v6 { "AAAA" } else { "A" };
+    let published = match client
+        .get_record_set(
+            record_set.zone_id,
+            record_set.name,
+            record_type,
+            record_set.routing.map(|routing| routing.set_identifier),
+        )
+        .await
+    {
+        Ok(entry) => entry
+            .map(|entry| route53_client::published_addresses(&entry))
+            .unwrap_or_default(),
+        Err(err) => {
+            report.error = Some(match report.error.take() {
+                Some(error) => format!("{}; Route53 error: {}", error, err),
+                None => format!("Route53 error: {}", err),
+            });
+            return false;
+        }
+    };
+
+    report.published_ip = match report.detected_ip {
+        Some(ip) if published.contains(&ip) => Some(ip),
+        _ => published.first().copied(),
+    };
+    true
+}
+
+/// Shows the detected address against the published one for each record
+/// set, including the hosts on the delegated prefix
+pub async fn status(
+    config: &AppConfig,
+    client: &Route53Client,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let state = if config.uses_state() {
+        State::load(&config.state_file())
+    } else {
+        State::default()
+    };
+
+    let mut reports = Vec::new();
+    let mut failures = 0;
+    for record_set in config.record_sets() {
+        let is_v6 = record_set.is_v6;
+        let mut report =
+            RecordReport::new(record_set.name, record_set.zone_id, is_v6);
+
+        // Address waiting to be stable since the last run
+        let countdown = match (&config.stabilization, state.get(&record_set)) {
+            (Some(stabilization), Some(record_state)) => {
+                record_state.countdown(stabilization, state::now())
+            }
+            _ => None,
+        };
+        if let Some(countdown) = countdown {
+            report.set_pending(&countdown);
+        }
+
+        match ip_address::record_set_address(&record_set).await {
+            Ok((ip, source)) => {
+                report.detected_ip = Some(ip);
+                report.provider = Some(source);
+            }
+            Err(err) => report.error = Some(err.to_string()),
+        }
+
+        if !add_published(client, &record_set, &mut report).await {
+            failures += 1;
+        }
+        reports.push(report);
+    }
+
+    // The hosts on the delegated IPv6 prefix, which share its detection
+    if let Some(prefix_config) = &config.prefix {
+        let addresses =
+            match prefix::current(prefix_config, &config.providers(true)).await
+            {
+                Ok((prefix, source)) => {
+                    prefix::host_addresses(prefix_config, &prefix)
+                        .map(|addresses| (addresses, source))
+                }
+                Err(err) => Err(err.to_string()),
+            };
+
+        let record_sets = config.prefix_record_sets();
+        for (idx, record_set) in record_sets.iter().enumerate() {
+            let mut report =
+                RecordReport::new(record_set.name, record_set.zone_id, true);
+            match &addresses {
+                Ok((addresses, source)) => {
+                    report.detected_ip = addresses.get(idx).map(|(_, ip)| *ip);
+                    report.provider = Some(source.clone());
+                }
+                Err(err) => report.error = Some(err.clone()),
+            }
+
+            if !add_published(client, record_set, &mut report).await {
+                failures += 1;
+            }
+            reports.push(report);
+        }
+    }
+
+    let result = if failures == 0 {
+        Ok(())
+    } else {
+        Err(
+            format!("Failed to read {} record set(s) from Route53", failures)
+                .into(),
+        )
+    };
+
+    if output == OutputFormat::Json {
+        report::print_json(&reports);
+        return result;
+    }
+
+    for report in reports.iter() {
+        let published = report
+            .published_ip
+            .map_or(String::from("nothing"), |ip| ip.to_string());
+
+        match (&report.detected_ip, &report.error) {
+            (Some(ip), Some(err)) if report.published_ip.is_none() => println!(
+                "{} ({}): detected {}; {}",
+                report.name, report.family, ip, err
+            ),
+            (Some(ip), _) if report.published_ip == Some(*ip) => println!(
+                "{} ({}): {} is published, up to date",
+                report.name, report.family, ip
+            ),
+            (Some(ip), _) => println!(
+                "{} ({}): detected {}, but {} is published",
+                report.name, report.family, ip, published
+            ),
+            (None, Some(err)) => println!(
+                "{} ({}): {} is published; {}",
+                report.name, report.family, published, err
+            ),
+            (None, None) => println!(
+                "{} ({}): {} is published",
+                report.name, report.family, published
+            ),
+        }
+
+        if let Some(ip) = report.pending_ip {
+            let countdown = state::Countdown {
+                ip,
+                checks_left: report.pending_checks_left,
+                seconds_left: report.pending_seconds_left,
+            };
+            println!("  {}", countdown);
+        }
+    }
+
+    result
+}
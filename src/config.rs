@@ -1,67 +1,978 @@
-use crate::MyIpProvider;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::ip_address::{self, MyIpProvider};
+use crate::route53_client::{is_same_name, Route53Client};
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Name of the configuration file on each directory of the search path
+pub const CONFIG_FILE_NAME: &str = "rust-aws-ddns.yml";
+
+/// Prefix of the environment variables overriding configuration fields
+const ENV_PREFIX: &str = "RUST_AWS_DDNS_";
+
+/// Top-level keys holding strings, whose overrides aren't parsed as YAML:
+/// `RUST_AWS_DDNS_OWNER_ID=123` stays the string "123"
+const STRING_KEYS: &[&str] = &[
+    "zone_id",
+    "owner_id",
+    "state_file",
+    "aws_access_key",
+    "aws_secret_access_key",
+];
+
+/// Keys whose lists are concatenated across layers instead of replaced
+const APPENDED_KEYS: [&str; 2] = ["records", "webhooks"];
+
+/// TTL of the record sets when the configuration doesn't set one
+pub const DEFAULT_TTL: i64 = 120;
+
+/// Seconds between drift checks when the configuration doesn't set them
+const DEFAULT_DRIFT_INTERVAL: u64 = 3600;
+
+/// Seconds without changes before the adaptive TTL goes up a step
+const DEFAULT_TTL_STEP: i64 = 3600;
+
+/// Current version of the configuration schema. Files without `version`
+/// are on version 1, see `migrate`.
+pub const CONFIG_VERSION: u64 = 2;
+
+/// Every key of the schema, used to suggest the right one for unknown keys
+const KNOWN_KEYS: [&str; 46] = [
+    "version",
+    "zone_id",
+    "records",
+    "name",
+    "ipv4",
+    "ipv6",
+    "ttl",
+    "check_before_updating",
+    "providers",
+    "provider_v6",
+    "prefix",
+    "interface",
+    "length",
+    "hosts",
+    "interface_id",
+    "mac",
+    "subnet",
+    "stabilization",
+    "checks",
+    "seconds",
+    "state_file",
+    "adaptive_ttl",
+    "min",
+    "max",
+    "step_seconds",
+    "on_drift",
+    "drift_interval",
+    "owner_id",
+    "on_conflict",
+    "set_identifier",
+    "weight",
+    "failover",
+    "multivalue",
+    "health_check_id",
+    "aws_access_key",
+    "aws_secret_access_key",
+    "webhooks",
+    "url",
+    "template",
+    "content_type",
+    "retries",
+    "notify_failures_after",
+    "on_change",
+    "on_failure",
+    "command",
+    "timeout",
+];
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct AppConfig {
+    /// Schema version
+    #[serde(default)]
+    pub version: u64,
+
+    /// Hosted zone of the record sets that don't set one
+    #[serde(default)]
     pub zone_id: String,
-    pub record_set: String,
-    pub record_set_v6: Option<String>,
-    pub update_ipv4: bool,
-    pub update_ipv6: bool,
+    #[serde(default)]
+    pub records: Vec<RecordConfig>,
+
+    /// TTL of the record sets, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub check_before_updating: Option<bool>,
 
-    pub provider_v4: Option<String>,
+    /// Services used to discover the public addresses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub providers: Option<ProvidersConfig>,
+
+    /// IPv6 providers when `providers` doesn't list any
+    #[serde(
+        default,
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub provider_v6: Vec<String>,
+
+    /// Written on the ownership markers of the record sets, to tell apart
+    /// the hosts updating the same zone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_id: Option<String>,
 
+    /// Seconds between the drift checks of the daemon, see `on_drift`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drift_interval: Option<u64>,
+
+    /// Lowers the TTL when the address changes, raising it while it stays
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adaptive_ttl: Option<AdaptiveTtlConfig>,
+
+    /// Waits for new addresses to be stable before publishing them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stabilization: Option<StabilizationConfig>,
+
+    /// Where the state kept between runs is written, see
+    /// `state::default_file`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_file: Option<String>,
+
+    /// AAAA records of LAN hosts on the delegated IPv6 prefix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<PrefixConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub aws_access_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub aws_secret_access_key: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhooks: Option<Vec<WebhookConfig>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_change: Option<HookConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_failure: Option<HookConfig>,
+
+    /// Keys found on the files that aren't part of the schema
+    #[serde(skip)]
+    pub unknown_keys: Vec<String>,
+}
+
+/// Record set kept updated with the public addresses
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct RecordConfig {
+    pub name: String,
+
+    /// Hosted zone of the record set, defaults to `zone_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zone_id: Option<String>,
+
+    /// Updates the A record, defaults to true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv4: Option<bool>,
+    /// Updates the AAAA record, defaults to false
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<bool>,
+
+    /// Publishes the address of the network interface instead of the one
+    /// reported by the providers, e.g. the LAN address on a private zone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+
+    /// What the daemon does when someone else changes the record set,
+    /// defaults to `ignore`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_drift: Option<DriftPolicy>,
+
+    /// What is done when the record set is an alias, has a routing policy
+    /// or several values, defaults to `refuse`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_conflict: Option<ConflictPolicy>,
+
+    /// Identifies the record set among the ones sharing its name with a
+    /// routing policy: `weight`, `failover` or `multivalue`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub set_identifier: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failover: Option<FailoverRole>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multivalue: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_check_id: Option<String>,
+}
+
+/// Role of a record set on a failover routing policy
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailoverRole {
+    Primary,
+    Secondary,
+}
+
+impl FailoverRole {
+    /// Returns the value Route53 uses for the role
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailoverRole::Primary => "PRIMARY",
+            FailoverRole::Secondary => "SECONDARY",
+        }
+    }
+}
+
+/// Handling of existing record sets that aren't simple ones with one value,
+/// which publishing the address would replace
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Fails the update
+    Refuse,
+    /// Deletes them and writes a simple record set
+    Replace,
+    /// Leaves them as they are, only logging it
+    Skip,
+}
+
+/// Handling of record sets changed outside of the tool
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DriftPolicy {
+    /// Publishes the last values again
+    Fix,
+    /// Only logs and notifies the webhooks
+    Alert,
+    Ignore,
+}
+
+impl RecordConfig {
+    pub fn updates_ipv4(&self) -> bool {
+        self.ipv4.unwrap_or(true)
+    }
+
+    pub fn updates_ipv6(&self) -> bool {
+        self.ipv6.unwrap_or(false)
+    }
+
+    /// Returns the routing policy of the record set, if it has a set
+    /// identifier
+    pub fn routing(&self) -> Option<Routing<'_>> {
+        Some(Routing {
+            set_identifier: self.set_identifier.as_deref()?,
+            weight: self.weight,
+            failover: self.failover,
+            multivalue: self.multivalue.unwrap_or(false),
+            health_check_id: self.health_check_id.as_deref(),
+        })
+    }
+
+    /// Returns the problems of the routing policy settings
+    fn routing_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let policies = [
+            self.weight.is_some(),
+            self.failover.is_some(),
+            self.multivalue == Some(true),
+        ];
+        let policies = policies.iter().filter(|is_set| **is_set).count();
+
+        match &self.set_identifier {
+            None => {
+                if policies > 0 || self.health_check_id.is_some() {
+                    problems.push(format!(
+                        "\"{}\" needs a set_identifier for weight, failover, \
+                         multivalue or health_check_id",
+                        self.name
+                    ));
+                }
+            }
+            Some(set_identifier) => {
+                let is_valid = !set_identifier.is_empty()
+                    && set_identifier.len() <= 128
+                    && !set_identifier.contains([',', '"']);
+                if !is_valid {
+                    problems.push(format!(
+                        "invalid set_identifier \"{}\" for \"{}\": use 1 to \
+                         128 characters other than commas and quotes",
+                        set_identifier, self.name
+                    ));
+                }
+                if policies != 1 {
+                    problems.push(format!(
+                        "\"{}\" needs exactly one of weight, failover or \
+                         multivalue along with its set_identifier",
+                        self.name
+                    ));
+                }
+            }
+        }
+
+        if let Some(weight) = self.weight {
+            if !(0..=255).contains(&weight) {
+                problems.push(format!(
+                    "weight {} of \"{}\" is not between 0 and 255",
+                    weight, self.name
+                ));
+            }
+        }
+        problems
+    }
+}
+
+/// Routing policy of a record set sharing its name with others
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Routing<'a> {
+    pub set_identifier: &'a str,
+    pub weight: Option<i64>,
+    pub failover: Option<FailoverRole>,
+    pub multivalue: bool,
+    pub health_check_id: Option<&'a str>,
+}
+
+impl Routing<'_> {
+    /// Returns the name of the routing policy
+    pub fn policy(&self) -> &'static str {
+        if self.weight.is_some() {
+            "weighted"
+        } else if self.failover.is_some() {
+            "failover"
+        } else {
+            "multi-value answer"
+        }
+    }
+}
+
+/// Providers of each address family, tried in order. Every provider of the
+/// family is tried when none is set.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ProvidersConfig {
+    #[serde(
+        default,
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub ipv4: Vec<String>,
+    #[serde(
+        default,
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub ipv6: Vec<String>,
+}
+
+/// The TTL drops to `min` when the address changes and doubles, up to `max`,
+/// after each `step_seconds` without changes
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AdaptiveTtlConfig {
+    pub min: i64,
+    pub max: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step_seconds: Option<i64>,
+}
+
+impl AdaptiveTtlConfig {
+    pub fn step_seconds(&self) -> i64 {
+        self.step_seconds.unwrap_or(DEFAULT_TTL_STEP)
+    }
+}
+
+/// A new address is published once it was seen on `checks` consecutive
+/// checks or for `seconds`, whichever comes first
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StabilizationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checks: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seconds: Option<u64>,
+}
+
+/// Delegated IPv6 prefix, taken from an address of the interface or, without
+/// one, from the address reported by the IPv6 providers
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PrefixConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+
+    /// Length of the delegated prefix, e.g. 56 or 64
+    pub length: u8,
+
+    /// Hosted zone of the host records, defaults to `zone_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zone_id: Option<String>,
+
+    pub hosts: Vec<PrefixHostConfig>,
+}
+
+/// Host on the delegated prefix, identified by its interface identifier or
+/// by the MAC address its EUI-64 identifier derives from
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PrefixHostConfig {
+    pub name: String,
+
+    /// Host part of the address, e.g. `::10` or `::211:22ff:fe33:4455`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+
+    /// Subnet inside a prefix shorter than /64, used along with `mac`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subnet: Option<u64>,
+}
+
+/// Accepts a single provider name as well as a list
+pub fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(name) => vec![name],
+        OneOrMany::Many(names) => names,
+    })
+}
+
+/// Record set to keep updated, with its hosted zone and the provider used to
+/// discover its address
+pub struct RecordSet<'a> {
+    pub name: &'a str,
+    pub zone_id: &'a str,
+    pub is_v6: bool,
+    pub providers: Vec<MyIpProvider>,
+
+    /// Interface whose address is published instead of the public one
+    pub interface: Option<&'a str>,
+    pub on_drift: DriftPolicy,
+    pub on_conflict: ConflictPolicy,
+    pub routing: Option<Routing<'a>>,
+}
+
+/// Command executed when a record set changes or fails to update
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HookConfig {
+    pub command: String,
+
+    /// Seconds to wait before killing the command
+    pub timeout: Option<u64>,
+}
+
+/// Webhook notified when a record set changes or its updates keep failing
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    pub url: String,
+
+    /// Request body; placeholders like `{{record}}` and `{{new_ip}}` are
+    /// replaced by the notification fields. Defaults to a JSON payload.
+    pub template: Option<String>,
+    pub content_type: Option<String>,
+    pub retries: Option<u32>,
+
+    /// Number of consecutive failures after which a notification is sent
+    pub notify_failures_after: Option<u32>,
 }
 
 impl AppConfig {
+    /// Loads the configuration, starting the wizard when there is none.
+    /// `config_file` replaces the search path, see `load`.
     pub async fn parse(
-        config_file: &str,
+        config_file: Option<&str>,
         is_in_quiet_mode: bool,
     ) -> Option<Self> {
-        // Opening and parsing the configuration file
-        let f = std::fs::File::open(config_file);
-        if let Err(io_err) = f {
-            use std::io::ErrorKind;
-
-            return match io_err.kind() {
-                ErrorKind::NotFound => {
-                    if is_in_quiet_mode {
-                        panic!("Configuration file not found!");
-                    }
+        let err = match AppConfig::load(config_file) {
+            Ok(config) => return Some(config),
+            Err(err) => err,
+        };
+
+        let is_not_found =
+            err.downcast_ref::<std::io::Error>().map(|err| err.kind())
+                == Some(std::io::ErrorKind::NotFound);
+        if !is_not_found {
+            error!("{}", err);
+            return None;
+        }
+
+        if is_in_quiet_mode {
+            panic!("Configuration file not found!");
+        }
+
+        info!("Config file not found! Starting configuration wizard");
+        AppConfig::run_config_wizard(&target_file(config_file), None).await
+    }
+
+    /// Loads the configuration, merging its layers: the configuration file
+    /// on each directory of the search path (or just `config_file`), each
+    /// followed by the `conf.d/*.yml` files next to it, and finally the
+    /// `RUST_AWS_DDNS_*` environment variables
+    pub fn load(
+        config_file: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = AppConfig::from_value(load_value(config_file)?)?;
+        for key in config.unknown_keys.iter() {
+            warn!("Ignoring unknown configuration key {}", key);
+        }
+        Ok(config)
+    }
+
+    /// Loads the configuration like `load`, but keeps it even if it has
+    /// problems, so that `validate` can report all of them
+    pub fn load_unchecked(
+        config_file: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        AppConfig::deserialize(load_value(config_file)?)
+    }
+
+    /// Loads a single configuration file, without merging other layers
+    pub fn load_file(
+        config_file: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        AppConfig::from_value(load_layer(Path::new(config_file))?)
+    }
+
+    /// Deserializes the (migrated) configuration, refusing the problems the
+    /// updates can't work around
+    fn from_value(value: Value) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = AppConfig::deserialize(value)?;
+        if let Some(problem) = config.fatal_problems().into_iter().next() {
+            return Err(problem.into());
+        }
+        Ok(config)
+    }
+
+    /// Deserializes the (migrated) configuration, collecting the keys that
+    /// aren't part of the schema
+    fn deserialize(value: Value) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut unknown_keys = Vec::new();
+        let mut config: AppConfig =
+            serde_ignored::deserialize(value, |path| {
+                unknown_keys.push(path.to_string())
+            })?;
+
+        config.unknown_keys = unknown_keys;
+        Ok(config)
+    }
+
+    /// Returns the problems that would break the updates: unknown providers
+    /// (skipped silently otherwise), routing policies, repeated records,
+    /// flap damping and adaptive TTL settings
+    fn fatal_problems(&self) -> Vec<String> {
+        let mut problems = self.provider_problems();
+        for record in self.records.iter() {
+            problems.extend(record.routing_problems());
+        }
+        problems.extend(self.duplicate_problems());
+        problems.extend(self.state_problems());
+        problems
+    }
+
+    /// Writes the configuration file atomically. It may hold AWS keys, so
+    /// only the owner can read it.
+    pub fn save(
+        &self,
+        config_file: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        write_private(config_file, &serde_yaml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Runs the wizard again, using the current configuration file (if any)
+    /// as the starting point
+    pub async fn configure(config_file: Option<&str>) -> Option<Self> {
+        let config_file = target_file(config_file);
+        let current = AppConfig::load_file(&config_file).ok();
+        AppConfig::run_config_wizard(&config_file, current).await
+    }
+
+    /// Returns the configuration as YAML, without showing the secret key
+    pub fn to_masked_yaml(&self) -> Result<String, serde_yaml::Error> {
+        let mut value = serde_yaml::to_value(self)?;
+        if let Value::Mapping(mapping) = &mut value {
+            let key = Value::String(String::from("aws_secret_access_key"));
+            if let Some(secret) = mapping.get_mut(&key) {
+                *secret = Value::String(String::from("********"));
+            }
+        }
+        serde_yaml::to_string(&value)
+    }
+
+    /// Checks the configuration without contacting AWS, returning the
+    /// problems found
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for key in self.unknown_keys.iter() {
+            let suggestion = key
+                .rsplit('.')
+                .next()
+                .and_then(suggest_key)
+                .map_or(String::new(), |known| {
+                    format!(" (did you mean \"{}\"?)", known)
+                });
+            problems.push(format!("unknown key \"{}\"{}", key, suggestion));
+        }
 
-                    println!(
-                        "Config file not found! Starting configuration wizard\n"
-                    );
-                    AppConfig::run_config_wizard(config_file).await
+        if self.records.is_empty() && self.prefix.is_none() {
+            problems.push(String::from("there are no records"));
+        }
+        for record in self.records.iter() {
+            if !is_valid_hostname(&record.name) {
+                problems.push(format!(
+                    "record \"{}\" is not a valid name",
+                    record.name
+                ));
+            }
+            if !record.updates_ipv4() && !record.updates_ipv6() {
+                problems.push(format!(
+                    "neither ipv4 nor ipv6 is enabled for \"{}\"",
+                    record.name
+                ));
+            }
+
+            let zone_id = record.zone_id.as_ref().unwrap_or(&self.zone_id);
+            if zone_id.trim().is_empty() {
+                problems.push(format!(
+                    "there is no zone_id for \"{}\"",
+                    record.name
+                ));
+            }
+
+            if let Some(interface) = &record.interface {
+                if interface.trim().is_empty() {
+                    problems.push(format!(
+                        "interface of \"{}\" is empty",
+                        record.name
+                    ));
                 }
-                _ => None,
-            };
+            }
+            problems.extend(record.routing_problems());
         }
 
-        let f = f.unwrap();
-        let config: AppConfig = serde_yaml::from_reader(f).unwrap();
-        Some(config)
+        problems.extend(self.duplicate_problems());
+        problems.extend(self.provider_problems());
+        if let Some(prefix) = &self.prefix {
+            problems.extend(self.prefix_problems(prefix));
+        }
+        problems.extend(self.state_problems());
+
+        if let Some(ttl) = self.ttl {
+            if ttl <= 0 {
+                problems.push(format!("ttl {} is not positive", ttl));
+            }
+        }
+
+        if let Some(owner_id) = &self.owner_id {
+            let is_valid = !owner_id.is_empty()
+                && owner_id
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() && c != ',' && c != '"');
+            if !is_valid {
+                problems.push(format!(
+                    "invalid owner_id \"{}\": use printable characters \
+                     other than commas and quotes",
+                    owner_id
+                ));
+            }
+        }
+
+        if self.aws_access_key.is_some() != self.aws_secret_access_key.is_some()
+        {
+            problems.push(String::from(
+                "aws_access_key and aws_secret_access_key must be set together",
+            ));
+        }
+
+        for webhook in self.webhooks.iter().flatten() {
+            if let Err(err) = reqwest::Url::parse(&webhook.url) {
+                problems.push(format!(
+                    "invalid webhook url \"{}\": {}",
+                    webhook.url, err
+                ));
+            }
+        }
+
+        let hooks = [
+            ("on_change", &self.on_change),
+            ("on_failure", &self.on_failure),
+        ];
+        for (key, hook) in hooks.iter() {
+            if let Some(hook) = hook {
+                if hook.command.trim().is_empty() {
+                    problems.push(format!("{} command is empty", key));
+                }
+            }
+        }
+
+        problems
     }
 
-    pub fn get_provider(&self) -> MyIpProvider {
-        let provider_str = if self.provider_v4.is_none() {
-            ""
-        } else {
-            self.provider_v4.as_ref().unwrap()
+    /// Returns the records repeated on a zone
+    fn duplicate_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        // The same name may only be repeated on different zones, e.g. for
+        // split-horizon DNS, with different set identifiers, or for another
+        // address family
+        for (idx, record) in self.records.iter().enumerate() {
+            let zone_id = record.zone_id.as_ref().unwrap_or(&self.zone_id);
+            let is_repeated = self.records[..idx].iter().any(|other| {
+                let shares_family = (other.updates_ipv4()
+                    && record.updates_ipv4())
+                    || (other.updates_ipv6() && record.updates_ipv6());
+                is_same_name(&other.name, &record.name)
+                    && other.zone_id.as_ref().unwrap_or(&self.zone_id)
+                        == zone_id
+                    && other.set_identifier == record.set_identifier
+                    && shares_family
+            });
+            if is_repeated {
+                problems.push(format!(
+                    "record \"{}\" appears twice on zone {}",
+                    record.name, zone_id
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Returns the problems of the flap damping and adaptive TTL settings
+    fn state_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if let Some(stabilization) = &self.stabilization {
+            if stabilization.checks.is_none() && stabilization.seconds.is_none()
+            {
+                problems.push(String::from(
+                    "stabilization needs checks or seconds",
+                ));
+            }
+            if stabilization.checks == Some(0) {
+                problems.push(String::from(
+                    "stabilization checks must be positive",
+                ));
+            }
+        }
+
+        if let Some(adaptive_ttl) = &self.adaptive_ttl {
+            if adaptive_ttl.min <= 0 || adaptive_ttl.min > adaptive_ttl.max {
+                problems.push(format!(
+                    "adaptive_ttl needs 0 < min <= max, got {} and {}",
+                    adaptive_ttl.min, adaptive_ttl.max
+                ));
+            }
+            if adaptive_ttl.step_seconds() <= 0 {
+                problems.push(String::from(
+                    "adaptive_ttl step_seconds must be positive",
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Returns the record sets to keep updated, along with their zones and
+    /// the providers used to discover their addresses
+    pub fn record_sets(&self) -> Vec<RecordSet<'_>> {
+        let mut record_sets = Vec::new();
+
+        // IPv4 first, and then IPv6
+        for is_v6 in [false, true].iter() {
+            for record in self.records.iter() {
+                let enabled = if *is_v6 {
+                    record.updates_ipv6()
+                } else {
+                    record.updates_ipv4()
+                };
+                if !enabled {
+                    continue;
+                }
+
+                record_sets.push(RecordSet {
+                    name: &record.name,
+                    zone_id: record.zone_id.as_ref().unwrap_or(&self.zone_id),
+                    is_v6: *is_v6,
+                    providers: self.providers(*is_v6),
+                    interface: record.interface.as_deref(),
+                    on_drift: record.on_drift.unwrap_or(DriftPolicy::Ignore),
+                    on_conflict: record
+                        .on_conflict
+                        .unwrap_or(ConflictPolicy::Refuse),
+                    routing: record.routing(),
+                });
+            }
+        }
+
+        record_sets
+    }
+
+    /// Returns true if a feature needs the state kept between runs
+    pub fn uses_state(&self) -> bool {
+        self.stabilization.is_some()
+            || self.adaptive_ttl.is_some()
+            || self.checks_drift()
+            || self.notifies_failures()
+    }
+
+    /// Returns true if a webhook is notified about failure streaks, which
+    /// are counted on the state
+    pub fn notifies_failures(&self) -> bool {
+        self.webhooks
+            .iter()
+            .flatten()
+            .any(|webhook| webhook.notify_failures_after.is_some())
+    }
+
+    /// Returns true if any record set is checked for drift
+    pub fn checks_drift(&self) -> bool {
+        self.records.iter().any(|record| {
+            record.on_drift.unwrap_or(DriftPolicy::Ignore)
+                != DriftPolicy::Ignore
+        })
+    }
+
+    /// Returns the file with the state kept between runs
+    pub fn state_file(&self) -> String {
+        match &self.state_file {
+            Some(state_file) => state_file.clone(),
+            None => crate::state::default_file(),
+        }
+    }
+
+    /// Returns true if the name is published on more than one zone, like a
+    /// private and a public one
+    pub fn is_split_horizon(&self, name: &str) -> bool {
+        self.records
+            .iter()
+            .filter(|record| is_same_name(&record.name, name))
+            .count()
+            > 1
+    }
+
+    /// Returns the AAAA record sets of the hosts on the delegated prefix
+    pub fn prefix_record_sets(&self) -> Vec<RecordSet<'_>> {
+        let prefix = match &self.prefix {
+            Some(prefix) => prefix,
+            None => return Vec::new(),
         };
 
-        match provider_str {
-            "httpbin" => MyIpProvider::Httpbin,
-            "identme" => MyIpProvider::IdentMe,
-            _ => MyIpProvider::Ipify,
+        prefix
+            .hosts
+            .iter()
+            .map(|host| RecordSet {
+                name: &host.name,
+                zone_id: prefix.zone_id.as_ref().unwrap_or(&self.zone_id),
+                is_v6: true,
+                providers: self.providers(true),
+                interface: None,
+                on_drift: DriftPolicy::Ignore,
+                on_conflict: ConflictPolicy::Refuse,
+                routing: None,
+            })
+            .collect()
+    }
+
+    fn prefix_problems(&self, prefix: &PrefixConfig) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if prefix.length == 0 || prefix.length > 64 {
+            problems.push(format!(
+                "prefix length {} is not between 1 and 64",
+                prefix.length
+            ));
+        }
+        if prefix.hosts.is_empty() {
+            problems.push(String::from("there are no prefix hosts"));
+        }
+        let zone_id = prefix.zone_id.as_ref().unwrap_or(&self.zone_id);
+        if zone_id.trim().is_empty() {
+            problems.push(String::from("there is no zone_id for the prefix"));
+        }
+
+        for host in prefix.hosts.iter() {
+            if !is_valid_hostname(&host.name) {
+                problems.push(format!(
+                    "prefix host \"{}\" is not a valid name",
+                    host.name
+                ));
+            }
+            if let Err(err) = crate::prefix::host_suffix(host, prefix.length) {
+                problems
+                    .push(format!("prefix host \"{}\": {}", host.name, err));
+            }
         }
+
+        problems
     }
 
-    /// Starts a wizard to generate a valid configuration file
-    async fn run_config_wizard(config_file: &str) -> Option<Self> {
+    /// Returns the configured provider names of the address family
+    fn provider_names(&self, is_v6: bool) -> &[String] {
+        match &self.providers {
+            Some(providers) if is_v6 && !providers.ipv6.is_empty() => {
+                &providers.ipv6
+            }
+            Some(providers) if !is_v6 => &providers.ipv4,
+            _ if is_v6 => &self.provider_v6,
+            _ => &[],
+        }
+    }
+
+    /// Returns the providers of the address family, in the configured order
+    pub fn providers(&self, is_v6: bool) -> Vec<MyIpProvider> {
+        self.provider_names(is_v6)
+            .iter()
+            .filter_map(|name| ip_address::find_provider(name, is_v6))
+            .collect()
+    }
+
+    fn provider_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (family, is_v6) in [("ipv4", false), ("ipv6", true)].iter() {
+            for name in self.provider_names(*is_v6).iter() {
+                if ip_address::find_provider(name, *is_v6).is_none() {
+                    problems.push(format!(
+                        "unknown {} provider \"{}\" (known: {})",
+                        family,
+                        name,
+                        ip_address::provider_names(*is_v6).join(", ")
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Returns the TTL of the record sets
+    pub fn ttl(&self) -> i64 {
+        self.ttl.unwrap_or(DEFAULT_TTL)
+    }
+
+    pub fn owner_id(&self) -> &str {
+        self.owner_id
+            .as_deref()
+            .unwrap_or(crate::route53_client::DEFAULT_OWNER)
+    }
+
+    pub fn drift_interval(&self) -> u64 {
+        self.drift_interval.unwrap_or(DEFAULT_DRIFT_INTERVAL)
+    }
+
+    /// Starts a wizard to generate a valid configuration file. Settings not
+    /// covered by the wizard are kept from the current configuration.
+    async fn run_config_wizard(
+        config_file: &str,
+        current: Option<AppConfig>,
+    ) -> Option<Self> {
+        let mut current = current.unwrap_or_default();
+
         loop {
             // AWS Access Key
             print!("AWS Access Key [Blank for env / system credentials]: ");
@@ -80,21 +991,17 @@ impl AppConfig {
             };
 
             // Fetch hosted zones
-            let hosted_zone_id: &str;
-            let hosted_zone_name: &str;
-            let credentials = if aws_access_key.is_none() {
-                None
-            } else {
-                let access_key = String::from(aws_access_key.as_ref().unwrap());
-                let secret_key = String::from(aws_secret_key.as_ref().unwrap());
-
-                Some(crate::aws_credentials::AppAwsCredentials {
-                    access_key,
-                    secret_access_key: secret_key,
-                })
+            let credentials = match (&aws_access_key, &aws_secret_key) {
+                (Some(access_key), Some(secret_key)) => {
+                    Some(crate::aws_credentials::AppAwsCredentials {
+                        access_key: access_key.clone(),
+                        secret_access_key: secret_key.clone(),
+                    })
+                }
+                _ => None,
             };
 
-            let client = crate::route53_client::Route53Client::new(credentials);
+            let client = Route53Client::new(credentials);
             let hosted_zones = client.list_hosted_zones().await;
 
             if hosted_zones.is_none() {
@@ -103,82 +1010,694 @@ impl AppConfig {
                 continue;
             }
 
-            let hosted_zones = hosted_zones.unwrap();
-            if hosted_zones.is_empty() {
-                panic!("There are no configured Hosted Zones on this Route 53 account. Please create a hosted zone and run this application again.");
-            }
-
             // Ask for zone_id
-            loop {
-                println!("\nSelect the desired Hosted Zone:");
-                hosted_zones.iter().enumerate().for_each(|entry| {
-                    println!(
-                        "{}. {} ({})",
-                        entry.0 + 1,
-                        (entry.1).1,
-                        (entry.1).0
-                    )
-                });
-
-                let hosted_zone_idx = read_int("Hosted Zone: ");
-                if hosted_zone_idx > 0 {
-                    let hosted_zone_idx = hosted_zone_idx as usize;
-                    if hosted_zone_idx <= hosted_zones.len() {
-                        let hosted_zone = &hosted_zones[hosted_zone_idx - 1];
-                        hosted_zone_id = &hosted_zone.0;
-                        hosted_zone_name = &hosted_zone.1;
+            let hosted_zones = hosted_zones.unwrap();
+            let (hosted_zone_id, hosted_zone_name) = match select_hosted_zone(
+                &client,
+                &hosted_zones,
+                &current,
+            )
+            .await
+            {
+                Some(hosted_zone) => hosted_zone,
+                None => continue,
+            };
 
-                        break;
+            // Existing address records, to pick from
+            let record_sets =
+                match client.list_record_sets(&hosted_zone_id).await {
+                    Ok(record_sets) => record_sets,
+                    Err(err) => {
+                        println!("\nError fetching the record sets: {}\n", err);
+                        Vec::new()
                     }
-                }
-            }
+                };
+            let existing = |record_type: &str| -> Vec<String> {
+                record_sets
+                    .iter()
+                    .filter(|entry| entry.type_ == record_type)
+                    .map(|entry| entry.name.clone())
+                    .collect()
+            };
 
             // Ask for record_sets
             let update_ipv4 = read_int("Update IPv4 (0 - No): ") != 0;
             let update_ipv6 = read_int("Update IPv6 (0 - No): ") != 0;
 
-            let prompt =
-                format!("IPv4 record set prefix (xxx.{}): ", hosted_zone_name);
-            let record_set_v4 = read_non_blank_line(&prompt).to_lowercase();
-            let record_set_v4 =
-                format!("{}.{}", record_set_v4, hosted_zone_name);
+            // The wizard replaces the first records of each family,
+            // keeping the others
+            let current_v4 =
+                current.records.iter().position(|r| r.updates_ipv4());
+            let current_v6 = current
+                .records
+                .iter()
+                .position(|r| r.updates_ipv6() && !r.updates_ipv4());
+            let current_name = |idx: Option<usize>| {
+                idx.map(|idx| current.records[idx].name.clone())
+            };
 
-            let record_set_v6 = if update_ipv6 {
-                print!("IPv6 record set prefix (xxx.{}): ", hosted_zone_name);
-                let record_set_v6_str = read_line().trim().to_lowercase();
+            let record_set_v4 = if update_ipv4 {
+                select_record_set(
+                    "IPv4",
+                    &hosted_zone_name,
+                    &existing("A"),
+                    current_name(current_v4).as_deref(),
+                    false,
+                )
+            } else {
+                None
+            };
 
-                if record_set_v6_str.is_empty() {
-                    None
-                } else {
-                    Some(format!("{}.{}", record_set_v6_str, hosted_zone_name))
-                }
+            // Optional with IPv4, where it defaults to the same record set
+            let record_set_v6 = if update_ipv6 {
+                select_record_set(
+                    "IPv6",
+                    &hosted_zone_name,
+                    &existing("AAAA"),
+                    current_name(current_v6).as_deref(),
+                    update_ipv4,
+                )
             } else {
                 None
             };
 
-            // Write configuration out
-            let config = AppConfig {
-                zone_id: String::from(hosted_zone_id),
-                record_set: record_set_v4,
-                record_set_v6,
+            let ttl = read_ttl(current.ttl());
+            let providers = ProvidersConfig {
+                ipv4: if update_ipv4 {
+                    select_providers(
+                        "IPv4",
+                        false,
+                        current.provider_names(false),
+                    )
+                } else {
+                    current.provider_names(false).to_vec()
+                },
+                ipv6: if update_ipv6 {
+                    select_providers("IPv6", true, current.provider_names(true))
+                } else {
+                    current.provider_names(true).to_vec()
+                },
+            };
+
+            let mut records = records_from(
+                record_set_v4.as_deref(),
+                record_set_v6.as_deref(),
                 update_ipv4,
                 update_ipv6,
-                check_before_updating: Some(true),
+            );
+            records.extend(
+                current
+                    .records
+                    .drain(..)
+                    .enumerate()
+                    .filter(|(idx, _)| {
+                        Some(*idx) != current_v4 && Some(*idx) != current_v6
+                    })
+                    .map(|(_, record)| record),
+            );
+
+            let config = AppConfig {
+                version: CONFIG_VERSION,
+                zone_id: hosted_zone_id,
+                records,
+                check_before_updating: current
+                    .check_before_updating
+                    .or(Some(true)),
+                ttl: Some(ttl),
 
-                provider_v4: None,
+                providers: Some(providers),
+                // Moved to `providers`
+                provider_v6: Vec::new(),
 
                 aws_access_key,
                 aws_secret_access_key: aws_secret_key,
+
+                // Not written out
+                unknown_keys: Vec::new(),
+
+                ..current
             };
 
-            let file = std::fs::File::create(config_file).unwrap();
-            serde_yaml::to_writer(file, &config).unwrap();
+            let problems = config.validate();
+            if !problems.is_empty() {
+                println!();
+                for problem in problems.iter() {
+                    println!("{}", problem);
+                }
+                // Starting over, with these answers as the defaults
+                println!();
+                current = config;
+                continue;
+            }
+
+            // Confirm before writing configuration out
+            let yaml = match config.to_masked_yaml() {
+                Ok(yaml) => yaml,
+                Err(err) => {
+                    error!("Failed to serialize the configuration: {}", err);
+                    return None;
+                }
+            };
+            println!("\n{}\n", yaml);
+            let answer = read_line_with_default(
+                &format!("Write this configuration to {}?", config_file),
+                "Y",
+            );
+            if !answer.eq_ignore_ascii_case("y") {
+                // Starting over, with these answers as the defaults
+                println!();
+                current = config;
+                continue;
+            }
+
+            if let Err(err) = config.save(config_file) {
+                error!("Failed to write {}: {}", config_file, err);
+                return None;
+            }
 
             return Some(config);
         }
     }
 }
 
+/// Asks for the hosted zone, offering to create a new one. Returns its id and
+/// name, or `None` to start over.
+async fn select_hosted_zone(
+    client: &Route53Client,
+    hosted_zones: &[(String, String)],
+    current: &AppConfig,
+) -> Option<(String, String)> {
+    if hosted_zones.is_empty() {
+        println!("\nThere are no hosted zones on this Route 53 account.");
+        return create_hosted_zone(client).await;
+    }
+
+    loop {
+        println!("\nSelect the desired Hosted Zone:");
+        println!("0. Create a new hosted zone");
+        for (idx, (zone_id, zone_name)) in hosted_zones.iter().enumerate() {
+            let marker = if *zone_id == current.zone_id {
+                " [current]"
+            } else {
+                ""
+            };
+            println!("{}. {} ({}){}", idx + 1, zone_name, zone_id, marker);
+        }
+
+        let hosted_zone_idx = read_int("Hosted Zone: ");
+        if hosted_zone_idx == 0 {
+            return create_hosted_zone(client).await;
+        }
+        if hosted_zone_idx > 0 && hosted_zone_idx as usize <= hosted_zones.len()
+        {
+            return Some(hosted_zones[hosted_zone_idx as usize - 1].clone());
+        }
+    }
+}
+
+/// Creates a hosted zone and shows the name servers to delegate it to
+async fn create_hosted_zone(
+    client: &Route53Client,
+) -> Option<(String, String)> {
+    let domain = loop {
+        let domain = read_non_blank_line("Domain name for the new zone: ");
+        if is_valid_hostname(&domain) && !domain.starts_with('*') {
+            break domain.to_lowercase();
+        }
+        println!("\"{}\" is not a valid domain name", domain);
+    };
+
+    match client.create_hosted_zone(&domain).await {
+        Ok((zone_id, zone_name, name_servers)) => {
+            println!(
+                "\nCreated {} ({}). Delegate the domain to these name servers \
+                 at your registrar:",
+                zone_name, zone_id
+            );
+            for name_server in name_servers.iter() {
+                println!("  {}", name_server);
+            }
+            println!();
+
+            Some((zone_id, zone_name))
+        }
+        Err(err) => {
+            println!("\nError creating the hosted zone: {}\n", err);
+            None
+        }
+    }
+}
+
+/// Asks for a record set, either one of the existing ones or a new prefix on
+/// the zone. With `optional` a blank answer returns `None`.
+fn select_record_set(
+    family: &str,
+    zone_name: &str,
+    existing: &[String],
+    current: Option<&str>,
+    optional: bool,
+) -> Option<String> {
+    if !existing.is_empty() {
+        println!("\nExisting {} record sets:", family);
+        for (idx, name) in existing.iter().enumerate() {
+            println!("{}. {}", idx + 1, name);
+        }
+    }
+
+    let prompt = format!(
+        "{} record set (number or prefix for xxx.{})",
+        family, zone_name
+    );
+    let current_prefix =
+        current.and_then(|name| record_set_prefix(name, zone_name));
+
+    loop {
+        let answer = match &current_prefix {
+            Some(prefix) => read_line_with_default(&prompt, prefix),
+            None => {
+                print!("{}: ", prompt);
+                read_line().trim().to_string()
+            }
+        };
+
+        if answer.is_empty() {
+            if optional {
+                return None;
+            }
+            continue;
+        }
+
+        if let Ok(idx) = answer.parse::<usize>() {
+            if idx > 0 && idx <= existing.len() {
+                return Some(existing[idx - 1].clone());
+            }
+        }
+
+        let name = format!("{}.{}", answer.to_lowercase(), zone_name);
+        if is_valid_hostname(&name) {
+            return Some(name);
+        }
+        println!("\"{}\" is not a valid name", name);
+    }
+}
+
+fn read_ttl(default: i64) -> i64 {
+    loop {
+        let answer =
+            read_line_with_default("TTL in seconds", &default.to_string());
+        match answer.parse::<i64>() {
+            Ok(ttl) if ttl > 0 => return ttl,
+            _ => println!("The TTL must be a positive number"),
+        }
+    }
+}
+
+/// Asks for the service used to discover the public addresses, returning
+/// it followed by the other providers of the family as fallbacks
+fn select_providers(
+    family: &str,
+    is_v6: bool,
+    current: &[String],
+) -> Vec<String> {
+    let names = ip_address::provider_names(is_v6);
+
+    println!("\n{} address provider:", family);
+    for (idx, name) in names.iter().enumerate() {
+        println!("{}. {}", idx + 1, name);
+    }
+
+    let current = current
+        .first()
+        .and_then(|name| names.iter().position(|provider| provider == name))
+        .unwrap_or(0);
+    loop {
+        let answer =
+            read_line_with_default("Provider", &(current + 1).to_string());
+        if let Ok(idx) = answer.parse::<usize>() {
+            if idx > 0 && idx <= names.len() {
+                return provider_chain(names[idx - 1], is_v6);
+            }
+        }
+    }
+}
+
+/// Returns the provider followed by the other ones of the address family
+pub fn provider_chain(first: &str, is_v6: bool) -> Vec<String> {
+    let mut chain = vec![first.to_string()];
+    for name in ip_address::provider_names(is_v6).into_iter() {
+        if name != first {
+            chain.push(name.to_string());
+        }
+    }
+    chain
+}
+
+/// Directories searched for the configuration, from the most general to the
+/// most specific: `/etc/rust-aws-ddns`, the XDG config directory and the
+/// current directory
+pub fn search_path() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from("/etc/rust-aws-ddns")];
+
+    let xdg_config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME")
+                .map(|home| Path::new(&home).join(".config"))
+        });
+    if let Some(xdg_config_home) = xdg_config_home {
+        dirs.push(xdg_config_home.join("rust-aws-ddns"));
+    }
+
+    dirs.push(PathBuf::from("."));
+    dirs
+}
+
+/// Returns the existing files making up the configuration, in the order they
+/// are merged
+pub fn layers(config_file: Option<&str>) -> Vec<PathBuf> {
+    let main_files = match config_file {
+        Some(config_file) => vec![PathBuf::from(config_file)],
+        None => search_path()
+            .into_iter()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .collect(),
+    };
+
+    let mut layers = Vec::new();
+    for main_file in main_files.into_iter() {
+        let conf_d = main_file
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join("conf.d");
+        if main_file.is_file() {
+            layers.push(main_file);
+        }
+
+        let mut drop_ins: Vec<PathBuf> = match std::fs::read_dir(&conf_d) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    path.is_file()
+                        && path.extension() == Some(OsStr::new("yml"))
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        drop_ins.sort();
+        layers.extend(drop_ins);
+    }
+
+    layers
+}
+
+/// Returns the file the wizard and `init` write to: `config_file`, or the
+/// most specific configuration file found on the search path, or
+/// `rust-aws-ddns.yml` on the current directory
+pub fn target_file(config_file: Option<&str>) -> String {
+    if let Some(config_file) = config_file {
+        return String::from(config_file);
+    }
+
+    search_path()
+        .into_iter()
+        .rev()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|path| path.is_file())
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| String::from(CONFIG_FILE_NAME))
+}
+
+/// Returns the merged configuration layers, before deserializing them
+pub fn load_value(
+    config_file: Option<&str>,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let layers = layers(config_file);
+    if layers.is_empty() {
+        let name = config_file.unwrap_or(CONFIG_FILE_NAME);
+        let err = std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} not found", name),
+        );
+        return Err(err.into());
+    }
+
+    let mut merged = Value::Mapping(Default::default());
+    for layer in layers.iter() {
+        merge(&mut merged, load_layer(layer)?);
+    }
+
+    apply_env_overrides(&mut merged, std::env::vars());
+    Ok(merged)
+}
+
+/// Reads a configuration file, migrating it to the current schema
+fn load_layer(path: &Path) -> Result<Value, Box<dyn std::error::Error>> {
+    let f = std::fs::File::open(path)?;
+    let mut value: Value = serde_yaml::from_reader(f)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    if value.is_null() {
+        value = Value::Mapping(Default::default());
+    }
+
+    migrate(&mut value)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(value)
+}
+
+/// Upgrades a configuration file written for an older schema, returning the
+/// version it was written for
+pub fn migrate(value: &mut Value) -> Result<u64, String> {
+    let config = match value {
+        Value::Mapping(config) => config,
+        _ => return Err(String::from("the configuration is not a mapping")),
+    };
+
+    let version_key = Value::from("version");
+    let version = match config.get(&version_key) {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| format!("invalid version {:?}", version))?,
+    };
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "version {} is newer than this program supports ({})",
+            version, CONFIG_VERSION
+        ));
+    }
+
+    if version < 2 {
+        migrate_v1(config);
+    }
+
+    // Keeping the version first
+    let mut migrated = serde_yaml::Mapping::new();
+    migrated.insert(version_key.clone(), Value::from(CONFIG_VERSION));
+    for (key, value) in std::mem::take(config).into_iter() {
+        if key != version_key {
+            migrated.insert(key, value);
+        }
+    }
+    *config = migrated;
+
+    Ok(version)
+}
+
+/// Version 1 had a single record set on `record_set` (with an optional
+/// `record_set_v6` for the AAAA record), enabled by `update_ipv4` and
+/// `update_ipv6`, and the provider on `provider_v4`. `provider_v6` is kept
+/// as it is, being a current key.
+fn migrate_v1(config: &mut serde_yaml::Mapping) {
+    let mut take = |key: &str| config.remove(&Value::from(key));
+
+    let record_set = take("record_set");
+    let record_set_v6 = take("record_set_v6");
+    let update_ipv4 = take("update_ipv4").and_then(|v| v.as_bool());
+    let update_ipv6 = take("update_ipv6").and_then(|v| v.as_bool());
+    let provider_v4 = take("provider_v4");
+
+    let name = record_set.as_ref().and_then(|v| v.as_str());
+    let name_v6 = record_set_v6.as_ref().and_then(|v| v.as_str());
+    let records = records_from(
+        name,
+        name_v6,
+        update_ipv4.unwrap_or(true),
+        update_ipv6.unwrap_or(false),
+    );
+
+    let records_key = Value::from("records");
+    if !records.is_empty() {
+        let mut migrated: Vec<Value> = records
+            .iter()
+            .filter_map(|record| serde_yaml::to_value(record).ok())
+            .collect();
+        if let Some(Value::Sequence(existing)) = config.remove(&records_key) {
+            migrated.extend(existing);
+        }
+        config.insert(records_key, Value::Sequence(migrated));
+    }
+
+    // The configured provider was tried first, followed by the others. For
+    // IPv6 identme was used along with its IPv4 provider, ipify otherwise,
+    // unless `provider_v6` picks one.
+    let provider_v4 = provider_v4.as_ref().and_then(|v| v.as_str());
+    if let Some(provider_v4) = provider_v4 {
+        let chain = |first, is_v6| -> Value {
+            let names = provider_chain(first, is_v6);
+            Value::Sequence(names.into_iter().map(Value::from).collect())
+        };
+        let mut providers = serde_yaml::Mapping::new();
+        providers.insert(Value::from("ipv4"), chain(provider_v4, false));
+        if !config.contains_key(&Value::from("provider_v6")) {
+            let provider_v6 = match provider_v4 {
+                "identme" => "identme",
+                _ => "ipify",
+            };
+            providers.insert(Value::from("ipv6"), chain(provider_v6, true));
+        }
+        config.insert(Value::from("providers"), Value::Mapping(providers));
+    }
+}
+
+/// Returns the records for a name updated with the enabled families, with
+/// an optional different name for the AAAA record
+pub fn records_from(
+    name: Option<&str>,
+    name_v6: Option<&str>,
+    ipv4: bool,
+    ipv6: bool,
+) -> Vec<RecordConfig> {
+    let mut records = Vec::new();
+
+    // The AAAA record on the same name is a single record
+    let name_v6 = name_v6.filter(|name_v6| match name {
+        Some(name) => !is_same_name(name, name_v6),
+        None => true,
+    });
+
+    let ipv6_on_name = ipv6 && name_v6.is_none();
+    if let Some(name) = name {
+        if ipv4 || ipv6_on_name {
+            records.push(RecordConfig {
+                name: name.to_string(),
+                zone_id: None,
+                ipv4: Some(ipv4),
+                ipv6: Some(ipv6_on_name),
+                ..Default::default()
+            });
+        }
+    }
+    if let (Some(name_v6), true) = (name_v6, ipv6) {
+        records.push(RecordConfig {
+            name: name_v6.to_string(),
+            zone_id: None,
+            ipv4: Some(false),
+            ipv6: Some(true),
+            ..Default::default()
+        });
+    }
+
+    records
+}
+
+/// Returns the known key closest to a misspelled one
+fn suggest_key(key: &str) -> Option<&'static str> {
+    KNOWN_KEYS
+        .iter()
+        .map(|known| (strsim::levenshtein(key, known), *known))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, known)| known)
+}
+
+/// Writes a file atomically, readable only by its owner
+pub fn write_private(
+    path: &str,
+    contents: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // A leftover temporary file could have other permissions
+    let tmp_file = format!("{}.tmp", path);
+    let _ = std::fs::remove_file(&tmp_file);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_file)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_file, path)?;
+    Ok(())
+}
+
+/// Merges a configuration layer into the previous ones. Mappings are merged
+/// key by key, the lists on `APPENDED_KEYS` are concatenated and any other
+/// value is replaced.
+fn merge(base: &mut Value, layer: Value) {
+    let (base, layer) = match (base, layer) {
+        (Value::Mapping(base), Value::Mapping(layer)) => (base, layer),
+        (base, layer) => {
+            *base = layer;
+            return;
+        }
+    };
+
+    for (key, value) in layer.into_iter() {
+        let is_appended =
+            matches!(key.as_str(), Some(key) if APPENDED_KEYS.contains(&key));
+
+        match (base.get_mut(&key), value) {
+            (Some(Value::Sequence(items)), Value::Sequence(more))
+                if is_appended =>
+            {
+                items.extend(more)
+            }
+            (Some(existing), value) => merge(existing, value),
+            (None, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Overrides configuration fields with the `RUST_AWS_DDNS_<FIELD>`
+/// environment variables, e.g. `RUST_AWS_DDNS_TTL=300`. Values are parsed
+/// as YAML, so lists and mappings can be given too, except for the string
+/// keys. Only top-level keys can be overridden.
+fn apply_env_overrides<I>(config: &mut Value, vars: I)
+where
+    I: Iterator<Item = (String, String)>,
+{
+    let config = match config {
+        Value::Mapping(config) => config,
+        _ => return,
+    };
+
+    for (name, value) in vars {
+        let key = match name.strip_prefix(ENV_PREFIX) {
+            Some(key) if !key.is_empty() => key.to_lowercase(),
+            _ => continue,
+        };
+
+        let value = if STRING_KEYS.contains(&key.as_str()) {
+            Value::String(value)
+        } else {
+            serde_yaml::from_str(&value)
+                .unwrap_or_else(|_| Value::String(value.clone()))
+        };
+        let key = Value::String(key);
+        match config.get_mut(&key) {
+            Some(existing) => *existing = value,
+            None => {
+                config.insert(key, value);
+            }
+        }
+    }
+}
+
 fn read_line() -> String {
     use std::io::{self, BufRead, Write};
 
@@ -192,6 +1711,51 @@ fn read_line() -> String {
     line
 }
 
+/// Returns the record set name without the zone name, if it belongs to it
+fn record_set_prefix(record_set: &str, zone_name: &str) -> Option<String> {
+    let suffix = format!(".{}", zone_name);
+    record_set
+        .strip_suffix(&suffix)
+        .filter(|prefix| !prefix.is_empty())
+        .map(String::from)
+}
+
+/// Returns true if the name is a valid DNS name (labels of letters, digits,
+/// hyphens and underscores, with an optional wildcard label first)
+pub fn is_valid_hostname(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > 253 {
+        return false;
+    }
+
+    name.split('.').enumerate().all(|(idx, label)| {
+        if idx == 0 && label == "*" {
+            return true;
+        }
+
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+fn read_line_with_default(prompt: &str, default: &str) -> String {
+    print!("{} [{}]: ", prompt, default);
+
+    let line = read_line();
+    let line = line.trim();
+
+    if line.is_empty() {
+        String::from(default)
+    } else {
+        String::from(line)
+    }
+}
+
 fn read_int(prompt: &str) -> i32 {
     loop {
         print!("{}", prompt);
@@ -218,3 +1782,308 @@ fn read_non_blank_line(prompt: &str) -> String {
         }
     }
 }
+
+#[cfg(test)]
+mod config_tests {
+    use super::{
+        apply_env_overrides, is_valid_hostname, merge, migrate,
+        record_set_prefix, AppConfig, FailoverRole,
+    };
+    use serde_yaml::Value;
+
+    #[test]
+    fn hostnames() {
+        assert!(is_valid_hostname("home.example.com."));
+        assert!(is_valid_hostname("home.example.com"));
+        assert!(is_valid_hostname("*.example.com."));
+        assert!(is_valid_hostname("_acme.example.com."));
+        assert!(!is_valid_hostname(""));
+        assert!(!is_valid_hostname("home..example.com."));
+        assert!(!is_valid_hostname("-home.example.com."));
+        assert!(!is_valid_hostname("my home.example.com."));
+        assert!(!is_valid_hostname("a.*.example.com."));
+        assert!(!is_valid_hostname(&format!("{}.com", "a".repeat(64))));
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(
+            record_set_prefix("home.example.com.", "example.com."),
+            Some(String::from("home"))
+        );
+        assert_eq!(
+            record_set_prefix("home.example.org.", "example.com."),
+            None
+        );
+        assert_eq!(record_set_prefix(".example.com.", "example.com."), None);
+    }
+
+    #[test]
+    fn layers() {
+        let mut config: Value = serde_yaml::from_str(
+            "zone_id: Z1\n\
+             record_set: home.example.com.\n\
+             records: [{name: a.example.com.}]\n\
+             on_change: {command: true, timeout: 5}\n",
+        )
+        .unwrap();
+        let drop_in: Value = serde_yaml::from_str(
+            "records: [{name: b.example.com.}]\n\
+             on_change: {command: reload}\n",
+        )
+        .unwrap();
+        merge(&mut config, drop_in);
+
+        let vars = vec![
+            (String::from("RUST_AWS_DDNS_TTL"), String::from("300")),
+            (String::from("RUST_AWS_DDNS_ZONE_ID"), String::from("Z2")),
+            (String::from("RUST_AWS_DDNS_OWNER_ID"), String::from("123")),
+            (String::from("RUST_AWS_DDNS_STATE_FILE"), String::from("no")),
+            (String::from("HOME"), String::from("/root")),
+        ];
+        apply_env_overrides(&mut config, vars.into_iter());
+
+        let expected: Value = serde_yaml::from_str(
+            "zone_id: Z2\n\
+             record_set: home.example.com.\n\
+             records: [{name: a.example.com.}, {name: b.example.com.}]\n\
+             on_change: {command: reload, timeout: 5}\n\
+             ttl: 300\n\
+             owner_id: \"123\"\n\
+             state_file: \"no\"\n",
+        )
+        .unwrap();
+        assert_eq!(config, expected);
+    }
+
+    #[test]
+    fn masked_secret() {
+        let config = AppConfig {
+            aws_access_key: Some(String::from("AKIDEXAMPLE")),
+            aws_secret_access_key: Some(String::from("secret")),
+            ..Default::default()
+        };
+        let yaml = config.to_masked_yaml().unwrap();
+        assert!(yaml.contains("aws_access_key: AKIDEXAMPLE"));
+        assert!(yaml.contains("aws_secret_access_key: \"********\""));
+        assert!(!yaml.contains("secret\n"));
+    }
+
+    #[test]
+    fn migration() {
+        let mut config: Value = serde_yaml::from_str(
+            "zone_id: Z1\n\
+             record_set: home.example.com.\n\
+             record_set_v6: home6.example.com.\n\
+             update_ipv4: true\n\
+             update_ipv6: true\n\
+             provider_v4: identme\n\
+             tll: 60\n",
+        )
+        .unwrap();
+        assert_eq!(migrate(&mut config), Ok(1));
+
+        let expected: Value = serde_yaml::from_str(
+            "version: 2\n\
+             zone_id: Z1\n\
+             tll: 60\n\
+             records:\n\
+             - {name: home.example.com., ipv4: true, ipv6: false}\n\
+             - {name: home6.example.com., ipv4: false, ipv6: true}\n\
+             providers:\n\
+             \x20 ipv4: [identme, ipify, httpbin]\n\
+             \x20 ipv6: [identme, ipify]\n",
+        )
+        .unwrap();
+        assert_eq!(config, expected);
+        assert_eq!(migrate(&mut config), Ok(2));
+
+        let config = AppConfig::from_value(config).unwrap();
+        assert_eq!(config.record_sets().len(), 2);
+        assert_eq!(config.unknown_keys, vec![String::from("tll")]);
+        assert!(config.validate().contains(&String::from(
+            "unknown key \"tll\" (did you mean \"ttl\"?)"
+        )));
+
+        let mut config: Value = serde_yaml::from_str("version: 3").unwrap();
+        assert!(migrate(&mut config).is_err());
+    }
+
+    #[test]
+    fn migration_same_names() {
+        // A and AAAA records on the same name were valid on version 1
+        let mut value: Value = serde_yaml::from_str(
+            "zone_id: Z1\n\
+             record_set: home.example.com.\n\
+             record_set_v6: home.example.com.\n\
+             update_ipv4: true\n\
+             update_ipv6: true\n",
+        )
+        .unwrap();
+        assert_eq!(migrate(&mut value), Ok(1));
+        let config = AppConfig::from_value(value).unwrap();
+        assert_eq!(config.records.len(), 1);
+        assert_eq!(config.record_sets().len(), 2);
+        assert!(config.validate().is_empty());
+
+        // Written as two records of different families
+        let value: Value = serde_yaml::from_str(
+            "version: 2\n\
+             zone_id: Z1\n\
+             records:\n\
+             - {name: home.example.com., ipv4: true, ipv6: false}\n\
+             - {name: home.example.com., ipv4: false, ipv6: true}\n",
+        )
+        .unwrap();
+        let config = AppConfig::from_value(value).unwrap();
+        assert!(config.validate().is_empty());
+
+        let value: Value = serde_yaml::from_str(
+            "version: 2\n\
+             zone_id: Z1\n\
+             records:\n\
+             - {name: home.example.com., ipv4: true, ipv6: true}\n\
+             - {name: home.example.com., ipv4: false, ipv6: true}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            AppConfig::from_value(value).unwrap_err().to_string(),
+            "record \"home.example.com.\" appears twice on zone Z1"
+        );
+    }
+
+    #[test]
+    fn provider_v6() {
+        let config: Value = serde_yaml::from_str(
+            "version: 2\n\
+             zone_id: Z1\n\
+             providers: {ipv4: httpbin}\n\
+             provider_v6: [identme, ipify]\n",
+        )
+        .unwrap();
+        let config = AppConfig::from_value(config).unwrap();
+        assert!(config.unknown_keys.is_empty());
+        assert_eq!(config.provider_names(true), ["identme", "ipify"]);
+
+        // `providers.ipv6` comes first
+        let config: Value = serde_yaml::from_str(
+            "version: 2\n\
+             zone_id: Z1\n\
+             providers: {ipv6: ipify}\n\
+             provider_v6: identme\n",
+        )
+        .unwrap();
+        let config = AppConfig::from_value(config).unwrap();
+        assert_eq!(config.provider_names(true), ["ipify"]);
+
+        // Kept by the migration instead of the provider picked for IPv4
+        let mut config: Value = serde_yaml::from_str(
+            "record_set: home.example.com.\n\
+             provider_v4: identme\n\
+             provider_v6: ipify\n",
+        )
+        .unwrap();
+        assert_eq!(migrate(&mut config), Ok(1));
+        let config = AppConfig::from_value(config).unwrap();
+        assert_eq!(config.provider_names(true), ["ipify"]);
+
+        let config: Value = serde_yaml::from_str(
+            "version: 2\n\
+             zone_id: Z1\n\
+             provider_v6: httpbin\n",
+        )
+        .unwrap();
+        assert!(AppConfig::from_value(config).is_err());
+    }
+
+    #[test]
+    fn fatal_problems() {
+        let configs = [
+            "stabilization: {checks: 0}",
+            "adaptive_ttl: {min: 600, max: 60}",
+            "adaptive_ttl: {min: 0, max: 60}",
+            "records: [{name: a.example.com., set_identifier: a}]",
+        ];
+        for config in configs.iter() {
+            let value: Value = serde_yaml::from_str(&format!(
+                "version: 2\nzone_id: Z1\n{}",
+                config
+            ))
+            .unwrap();
+            assert!(AppConfig::from_value(value).is_err(), "{}", config);
+        }
+    }
+
+    #[test]
+    fn split_horizon() {
+        let config: Value = serde_yaml::from_str(
+            "version: 2\n\
+             zone_id: PUBLIC\n\
+             records:\n\
+             - {name: nas.example.com., zone_id: PRIVATE, interface: eth0}\n\
+             - {name: nas.example.com.}\n\
+             - {name: NAS.example.com}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            AppConfig::from_value(config.clone())
+                .unwrap_err()
+                .to_string(),
+            "record \"NAS.example.com\" appears twice on zone PUBLIC"
+        );
+        let config = AppConfig::deserialize(config).unwrap();
+
+        let record_sets = config.record_sets();
+        assert_eq!(record_sets[0].zone_id, "PRIVATE");
+        assert_eq!(record_sets[0].interface, Some("eth0"));
+        assert_eq!(record_sets[1].interface, None);
+        assert!(config.is_split_horizon("nas.example.com."));
+        assert_eq!(
+            config.validate(),
+            vec![String::from(
+                "record \"NAS.example.com\" appears twice on zone PUBLIC"
+            )]
+        );
+    }
+
+    #[test]
+    fn routing() {
+        let config: Value = serde_yaml::from_str(
+            "version: 2\n\
+             zone_id: Z1\n\
+             records:\n\
+             - {name: www.example.com., set_identifier: home, weight: 10}\n\
+             - {name: www.example.com., set_identifier: office, weight: 300}\n\
+             - {name: vpn.example.com., set_identifier: a, failover: primary, \
+                health_check_id: abcd}\n\
+             - {name: ftp.example.com., weight: 10}\n\
+             - {name: ssh.example.com., set_identifier: b, multivalue: true, \
+                weight: 1}\n",
+        )
+        .unwrap();
+        assert!(AppConfig::from_value(config.clone()).is_err());
+        let config = AppConfig::deserialize(config).unwrap();
+
+        let routing = config.record_sets()[2].routing.unwrap();
+        assert_eq!(routing.set_identifier, "a");
+        assert_eq!(routing.failover, Some(FailoverRole::Primary));
+        assert_eq!(routing.health_check_id, Some("abcd"));
+        assert_eq!(routing.policy(), "failover");
+        assert_eq!(
+            config.validate(),
+            vec![
+                String::from(
+                    "weight 300 of \"www.example.com.\" is not between 0 and 255"
+                ),
+                String::from(
+                    "\"ftp.example.com.\" needs a set_identifier for weight, \
+                     failover, multivalue or health_check_id"
+                ),
+                String::from(
+                    "\"ssh.example.com.\" needs exactly one of weight, \
+                     failover or multivalue along with its set_identifier"
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+
+const CLASS_IN: u16 = 1;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct DnsError {
+    message: String,
+}
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DNS query failed: {}", self.message)
+    }
+}
+impl Error for DnsError {}
+
+fn dns_error(message: &str) -> DnsError {
+    DnsError {
+        message: message.to_string(),
+    }
+}
+
+/// Builds a non-recursive query for the name
+fn build_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut query = Vec::with_capacity(512);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0, 0]); // flags: standard query, no recursion
+    query.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+    query.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // ANCOUNT, NSCOUNT, ARCOUNT
+
+    for label in name.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    query
+}
+
+/// Returns the position right after the (possibly compressed) name
+fn skip_name(buf: &[u8], mut pos: usize) -> Result<usize, DnsError> {
+    loop {
+        let len = *buf.get(pos).ok_or_else(|| dns_error("truncated name"))?;
+        match len {
+            0 => return Ok(pos + 1),
+            len if len & 0xC0 == 0xC0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Result<u16, DnsError> {
+    match buf.get(pos..pos + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(dns_error("truncated response")),
+    }
+}
+
+/// Extracts the addresses of the requested type from the response
+fn parse_response(
+    id: u16,
+    buf: &[u8],
+    qtype: u16,
+) -> Result<Vec<IpAddr>, DnsError> {
+    if read_u16(buf, 0)? != id {
+        return Err(dns_error("unexpected response id"));
+    }
+
+    let flags = read_u16(buf, 2)?;
+    if flags & 0x8000 == 0 {
+        return Err(dns_error("not a response"));
+    }
+    match flags & 0x000F {
+        0 => {}
+        3 => return Ok(Vec::new()), // NXDOMAIN
+        rcode => return Err(dns_error(&format!("server returned {}", rcode))),
+    }
+
+    let question_count = read_u16(buf, 4)?;
+    let answer_count = read_u16(buf, 6)?;
+
+    let mut pos = 12;
+    for _ in 0..question_count {
+        pos = skip_name(buf, pos)? + 4;
+    }
+
+    let mut addresses = Vec::new();
+    for _ in 0..answer_count {
+        pos = skip_name(buf, pos)?;
+        let rtype = read_u16(buf, pos)?;
+        let rdlength = read_u16(buf, pos + 8)? as usize;
+        pos += 10;
+
+        let rdata = buf
+            .get(pos..pos + rdlength)
+            .ok_or_else(|| dns_error("truncated record"))?;
+        pos += rdlength;
+
+        if rtype != qtype {
+            continue;
+        }
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) => {
+                let octets = [rdata[0], rdata[1], rdata[2], rdata[3]];
+                addresses.push(IpAddr::V4(Ipv4Addr::from(octets)));
+            }
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                addresses.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(addresses)
+}
+
+/// Queries the server directly for the A or AAAA records of the name
+pub async fn query(
+    server: SocketAddr,
+    name: &str,
+    qtype: u16,
+) -> Result<Vec<IpAddr>, Box<dyn Error>> {
+    let id = (std::process::id() as u16)
+        ^ (std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .subsec_nanos() as u16);
+
+    let bind_addr = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let mut socket = UdpSocket::bind(bind_addr).await?;
+    socket
+        .send_to(&build_query(id, name, qtype), &server)
+        .await?;
+
+    let mut buf = [0u8; 4096];
+    let (len, _) = tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf))
+        .await
+        .map_err(|_| dns_error("timed out"))??;
+
+    Ok(parse_response(id, &buf[..len], qtype)?)
+}
+
+#[cfg(test)]
+mod dns_tests {
+    use super::*;
+
+    #[test]
+    fn query_encoding() {
+        let query = build_query(0x1234, "home.example.com.", TYPE_A);
+        assert_eq!(&query[..4], &[0x12, 0x34, 0, 0]);
+        assert_eq!(&query[12..17], b"\x04home");
+        assert_eq!(&query[query.len() - 5..], &[0, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn response_parsing() {
+        let mut response = build_query(0x1234, "home.example.com.", TYPE_A);
+        response[2] = 0x84; // QR + AA
+        response[7] = 2; // ANCOUNT
+
+        // Answers use a pointer to the question name (offset 12)
+        response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        response.extend_from_slice(&[192, 0, 2, 1]);
+        response.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2]);
+        response.extend_from_slice(&[0xC0, 12]);
+
+        let addresses = parse_response(0x1234, &response, TYPE_A).unwrap();
+        assert_eq!(addresses, vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+
+        assert!(parse_response(0x4321, &response, TYPE_A).is_err());
+
+        response[3] = 3; // NXDOMAIN
+        assert!(parse_response(0x1234, &response, TYPE_A)
+            .unwrap()
+            .is_empty());
+    }
+}
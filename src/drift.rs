@@ -0,0 +1,263 @@
+use std::net::IpAddr;
+
+use rusoto_route53::ResourceRecordSet;
+
+use crate::config::{AppConfig, DriftPolicy, RecordSet};
+use crate::notifications;
+use crate::route53_client::{self, Route53Client};
+use crate::state::{self, PublishedRecord, RecordState};
+
+/// Returns the values in a form that doesn't depend on how the addresses
+/// are written
+fn normalize(values: &[String]) -> Vec<String> {
+    let mut values: Vec<String> = values
+        .iter()
+        .map(|value| match value.parse::<IpAddr>() {
+            Ok(ip) => ip.to_string(),
+            Err(_) => value.clone(),
+        })
+        .collect();
+    values.sort();
+    values
+}
+
+/// Returns the record set as it is on Route53
+fn published_record(record_set: &ResourceRecordSet) -> PublishedRecord {
+    PublishedRecord {
+        record_type: record_set.type_.clone(),
+        values: record_set
+            .resource_records
+            .iter()
+            .flatten()
+            .map(|record| record.value.clone())
+            .collect(),
+        ttl: record_set.ttl.unwrap_or_default(),
+    }
+}
+
+/// Describes how the record sets found with the name differ from the one
+/// written by the tool, or returns None if it is still there unchanged
+pub fn describe(
+    written: &PublishedRecord,
+    found: &[ResourceRecordSet],
+) -> Option<String> {
+    let entry = match found
+        .iter()
+        .find(|entry| entry.type_ == written.record_type)
+    {
+        Some(entry) => entry,
+        None => {
+            // The other address family doesn't replace the record
+            let others: Vec<&str> = found
+                .iter()
+                .map(|entry| entry.type_.as_str())
+                .filter(|record_type| {
+                    *record_type != "A" && *record_type != "AAAA"
+                })
+                .collect();
+            return Some(if others.is_empty() {
+                format!("the {} record was deleted", written.record_type)
+            } else {
+                format!(
+                    "the {} record was replaced by {}",
+                    written.record_type,
+                    others.join(", ")
+                )
+            });
+        }
+    };
+    if entry.alias_target.is_some() {
+        return Some(format!(
+            "the {} record was turned into an alias",
+            written.record_type
+        ));
+    }
+
+    let published = published_record(entry);
+    let mut changes = Vec::new();
+    let (expected, values) =
+        (normalize(&written.values), normalize(&published.values));
+    if expected != values {
+        changes.push(format!(
+            "values changed from {} to {}",
+            expected.join(","),
+            values.join(",")
+        ));
+    }
+    if published.ttl != written.ttl {
+        changes.push(format!(
+            "TTL changed from {}s to {}s",
+            written.ttl, published.ttl
+        ));
+    }
+
+    if changes.is_empty() {
+        None
+    } else {
+        Some(changes.join(", "))
+    }
+}
+
+/// Compares the record sets with what the tool last wrote on them, fixing or
+/// reporting the differences according to their `on_drift`
+pub async fn check_all(config: &AppConfig, client: &Route53Client) {
+    let state_file = config.state_file();
+    let mut state = state::State::load(&state_file);
+
+    for record_set in config.record_sets() {
+        if record_set.on_drift == DriftPolicy::Ignore {
+            continue;
+        }
+
+        let res =
+            check(config, client, &record_set, state.record(&record_set)).await;
+        if let Err(err) = res {
+            error!(
+                record = record_set.name;
+                "Failed to check {} for drift: {}", record_set.name, err
+            );
+        }
+    }
+
+    if let Err(err) = state.save(&state_file) {
+        error!("Failed to save the state on {}: {}", state_file, err);
+    }
+}
+
+/// Returns the record sets with the name of the record set, leaving out the
+/// other record sets of its routing policy
+async fn fetch(
+    client: &Route53Client,
+    record_set: &RecordSet<'_>,
+) -> Result<Vec<ResourceRecordSet>, Box<dyn std::error::Error>> {
+    let mut found = client
+        .get_record_sets_named(record_set.zone_id, record_set.name)
+        .await?;
+
+    let record_type = if record_set.is_v6 { "AAAA" } else { "A" };
+    if let Some(routing) = &record_set.routing {
+        found.retain(|entry| {
+            entry.type_ != record_type
+                || entry.set_identifier.as_deref()
+                    == Some(routing.set_identifier)
+        });
+    }
+    Ok(found)
+}
+
+/// Describes how the record set on Route53 differs from the one written by
+/// the tool, or returns None if it is unchanged
+pub async fn changed_since(
+    client: &Route53Client,
+    record_set: &RecordSet<'_>,
+    written: &PublishedRecord,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    Ok(describe(written, &fetch(client, record_set).await?))
+}
+
+async fn check(
+    config: &AppConfig,
+    client: &Route53Client,
+    record_set: &RecordSet<'_>,
+    record_state: &mut RecordState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let name = record_set.name;
+    let found = fetch(client, record_set).await?;
+    let record_type = if record_set.is_v6 { "AAAA" } else { "A" };
+
+    let written = match &record_state.written {
+        Some(written) => written.clone(),
+        None => {
+            // Nothing written since the state started: the record set
+            // becomes the reference, if it has the published address
+            let entry = found.iter().find(|entry| {
+                entry.type_ == record_type && entry.alias_target.is_none()
+            });
+            if let (Some(ip), Some(entry)) = (record_state.stable_ip, entry) {
+                if route53_client::published_addresses(entry).contains(&ip) {
+                    record_state.written = Some(published_record(entry));
+                }
+            }
+            return Ok(());
+        }
+    };
+
+    let description = match describe(&written, &found) {
+        Some(description) => description,
+        None => {
+            record_state.drift = None;
+            return Ok(());
+        }
+    };
+
+    if record_set.on_drift == DriftPolicy::Fix {
+        warn!(
+            record = name, zone = record_set.zone_id;
+            "{} was changed outside of rust-aws-ddns ({}), fixing it",
+            name, description
+        );
+        let ip: IpAddr = written
+            .values
+            .first()
+            .and_then(|value| value.parse().ok())
+            .ok_or("no address to restore")?;
+        client
+            .restore_record_set(record_set, &ip, written.ttl)
+            .await?;
+        notifications::drift_detected(config, record_set, &description);
+        return Ok(());
+    }
+
+    // Alerting once, until the record set changes again
+    if record_state.drift.as_ref() != Some(&description) {
+        warn!(
+            record = name, zone = record_set.zone_id;
+            "{} was changed outside of rust-aws-ddns: {}", name, description
+        );
+        notifications::drift_detected(config, record_set, &description);
+        record_state.drift = Some(description);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod drift_tests {
+    use super::*;
+    use crate::route53_client::fixtures::entry;
+    use rusoto_route53::AliasTarget;
+
+    #[test]
+    fn describe_changes() {
+        let written = PublishedRecord {
+            record_type: String::from("AAAA"),
+            values: vec![String::from("2001:db8::1")],
+            ttl: 120,
+        };
+
+        let same = entry("AAAA", &["2001:0db8:0:0:0:0:0:1"], 120);
+        assert_eq!(describe(&written, &[entry("A", &[], 60), same]), None);
+
+        assert_eq!(
+            describe(&written, &[entry("AAAA", &["2001:db8::2"], 300)])
+                .unwrap(),
+            "values changed from 2001:db8::1 to 2001:db8::2, \
+             TTL changed from 120s to 300s"
+        );
+        assert_eq!(
+            describe(&written, &[entry("A", &["192.0.2.1"], 120)]).unwrap(),
+            "the AAAA record was deleted"
+        );
+        assert_eq!(
+            describe(&written, &[entry("CNAME", &["example.com."], 120)])
+                .unwrap(),
+            "the AAAA record was replaced by CNAME"
+        );
+
+        let mut alias = entry("AAAA", &[], 0);
+        alias.alias_target = Some(AliasTarget::default());
+        assert_eq!(
+            describe(&written, &[alias]).unwrap(),
+            "the AAAA record was turned into an alias"
+        );
+    }
+}
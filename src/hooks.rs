@@ -0,0 +1,104 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tokio::process::Command;
+
+use crate::config::{AppConfig, HookConfig, RecordSet};
+
+/// Seconds a hook may run when it doesn't set `timeout`
+const DEFAULT_TIMEOUT: u64 = 30;
+
+fn family_of(ip: &IpAddr) -> &'static str {
+    if ip.is_ipv4() {
+        "ipv4"
+    } else {
+        "ipv6"
+    }
+}
+
+/// Runs the hook command through the shell, killing it on timeout
+async fn run(
+    hook: &HookConfig,
+    name: &str,
+    record_set: &str,
+    env: &[(&str, String)],
+) {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(&hook.command).kill_on_drop(true);
+    for (key, value) in env.iter() {
+        command.env(key, value);
+    }
+
+    let timeout = Duration::from_secs(hook.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    let status = match command.spawn() {
+        Ok(child) => tokio::time::timeout(timeout, child).await,
+        Err(err) => {
+            error!(record = record_set; "{} hook failed to start: {}", name, err);
+            return;
+        }
+    };
+
+    match status {
+        Ok(Ok(status)) if status.success() => {
+            info!(record = record_set; "{} hook finished successfully", name)
+        }
+        Ok(Ok(status)) => {
+            error!(record = record_set; "{} hook failed: {}", name, status)
+        }
+        Ok(Err(err)) => {
+            error!(record = record_set; "{} hook failed: {}", name, err)
+        }
+        Err(_) => error!(
+            record = record_set;
+            "{} hook timed out after {} seconds",
+            name,
+            timeout.as_secs()
+        ),
+    }
+}
+
+/// Runs the `on_change` hook after a record set has been updated
+pub async fn address_changed(
+    config: &AppConfig,
+    record_set: &RecordSet<'_>,
+    old_ip: Option<IpAddr>,
+    new_ip: &IpAddr,
+) {
+    let hook = match &config.on_change {
+        Some(hook) => hook,
+        None => return,
+    };
+
+    let env = [
+        ("DDNS_RECORD", record_set.name.to_string()),
+        ("DDNS_ZONE", record_set.zone_id.to_string()),
+        (
+            "DDNS_OLD_IP",
+            old_ip.map_or(String::new(), |ip| ip.to_string()),
+        ),
+        ("DDNS_NEW_IP", new_ip.to_string()),
+        ("DDNS_FAMILY", family_of(new_ip).to_string()),
+    ];
+    run(hook, "on_change", record_set.name, &env).await;
+}
+
+/// Runs the `on_failure` hook after a record set update has failed
+pub async fn update_failed(
+    config: &AppConfig,
+    record_set: &RecordSet<'_>,
+    error: &dyn std::error::Error,
+) {
+    let hook = match &config.on_failure {
+        Some(hook) => hook,
+        None => return,
+    };
+
+    let family = if record_set.is_v6 { "ipv6" } else { "ipv4" };
+    let env = [
+        ("DDNS_RECORD", record_set.name.to_string()),
+        ("DDNS_ZONE", record_set.zone_id.to_string()),
+        ("DDNS_FAMILY", family.to_string()),
+        ("DDNS_ERROR", error.to_string()),
+    ];
+    run(hook, "on_failure", record_set.name, &env).await;
+}
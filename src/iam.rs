@@ -0,0 +1,147 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json::json;
+
+use crate::config::{AppConfig, ConflictPolicy};
+use crate::route53_client;
+
+/// Returns the record name in the form IAM compares it against on the
+/// `route53:ChangeResourceRecordSetsNormalizedRecordNames` condition:
+/// lowercase, without the trailing dot and with the wildcard escaped as
+/// Route53 stores it (`\052`)
+fn normalized_name(name: &str) -> String {
+    name.trim_end_matches('.')
+        .to_lowercase()
+        .replace('*', "\\052")
+}
+
+/// Names, record types and actions changed on a hosted zone
+type ZoneChanges<'a> = (BTreeSet<String>, BTreeSet<&'a str>, BTreeSet<&'a str>);
+
+fn zone_arn(zone_id: &str) -> String {
+    format!(
+        "arn:aws:route53:::hostedzone/{}",
+        zone_id.trim_start_matches("/hostedzone/")
+    )
+}
+
+/// Builds the smallest IAM policy that lets the tool update the configured
+/// record sets. With `include_wizard` the calls made by `configure` and
+/// `list-zones` are allowed too.
+pub fn policy(config: &AppConfig, include_wizard: bool) -> serde_json::Value {
+    // Names, record types and actions on each hosted zone
+    let mut zones: BTreeMap<String, ZoneChanges> = BTreeMap::new();
+    let record_sets = config.record_sets();
+    for record_set in record_sets.iter().chain(&config.prefix_record_sets()) {
+        let (names, types, actions) =
+            zones.entry(zone_arn(record_set.zone_id)).or_default();
+        actions.insert("UPSERT");
+        if record_set.on_conflict == ConflictPolicy::Replace {
+            actions.insert("DELETE");
+        }
+        names.insert(normalized_name(record_set.name));
+        types.insert(if record_set.is_v6 { "AAAA" } else { "A" });
+
+        // Along with its ownership marker
+        names.insert(normalized_name(&route53_client::owner_record_name(
+            record_set.name,
+        )));
+        types.insert("TXT");
+    }
+
+    let mut statements = Vec::new();
+    for (idx, (zone_arn, (names, types, actions))) in zones.iter().enumerate() {
+        statements.push(json!({
+            "Sid": format!("UpdateRecordSets{}", idx + 1),
+            "Effect": "Allow",
+            "Action": "route53:ChangeResourceRecordSets",
+            "Resource": zone_arn,
+            "Condition": {
+                "ForAllValues:StringEquals": {
+                    "route53:ChangeResourceRecordSetsNormalizedRecordNames":
+                        names,
+                    "route53:ChangeResourceRecordSetsRecordTypes": types,
+                    "route53:ChangeResourceRecordSetsActions": actions
+                }
+            }
+        }));
+    }
+
+    // Reading the current values and, for `check`, the name servers
+    let zone_arns: Vec<&String> = zones.keys().collect();
+    statements.push(json!({
+        "Sid": "ReadRecordSets",
+        "Effect": "Allow",
+        "Action": ["route53:ListResourceRecordSets", "route53:GetHostedZone"],
+        "Resource": zone_arns
+    }));
+
+    // The wizard lists and creates zones, and lists the record sets of the
+    // zone picked, which may not be configured yet
+    if include_wizard {
+        statements.push(json!({
+            "Sid": "Wizard",
+            "Effect": "Allow",
+            "Action": [
+                "route53:ListHostedZones",
+                "route53:CreateHostedZone",
+                "route53:ListResourceRecordSets"
+            ],
+            "Resource": "*"
+        }));
+    }
+
+    json!({
+        "Version": "2012-10-17",
+        "Statement": statements
+    })
+}
+
+#[cfg(test)]
+mod iam_tests {
+    use super::*;
+    use crate::config;
+
+    #[test]
+    fn record_sets_policy() {
+        let config = AppConfig {
+            zone_id: String::from("/hostedzone/Z0123"),
+            records: config::records_from(
+                Some("Home.example.com."),
+                Some("*.home6.example.com."),
+                true,
+                true,
+            ),
+            ..Default::default()
+        };
+
+        let document = policy(&config, false);
+        let statements = document["Statement"].as_array().unwrap();
+        assert_eq!(statements.len(), 2);
+
+        let update = &statements[0];
+        assert_eq!(update["Resource"], "arn:aws:route53:::hostedzone/Z0123");
+        assert_eq!(statements[1]["Resource"], json!([update["Resource"]]));
+        let condition = &update["Condition"]["ForAllValues:StringEquals"];
+        assert_eq!(
+            condition["route53:ChangeResourceRecordSetsNormalizedRecordNames"],
+            json!([
+                "\\052.home6.example.com",
+                "_rust-aws-ddns.\\052.home6.example.com",
+                "_rust-aws-ddns.home.example.com",
+                "home.example.com"
+            ])
+        );
+        assert_eq!(
+            condition["route53:ChangeResourceRecordSetsRecordTypes"],
+            json!(["A", "AAAA", "TXT"])
+        );
+        assert_eq!(
+            condition["route53:ChangeResourceRecordSetsActions"],
+            json!(["UPSERT"])
+        );
+
+        let document = policy(&config, true);
+        assert_eq!(document["Statement"].as_array().unwrap().len(), 3);
+    }
+}
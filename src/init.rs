@@ -0,0 +1,199 @@
+use clap::ArgMatches;
+use serde::Deserialize;
+
+use crate::config::{self, AppConfig};
+use crate::route53_client::{self, Route53Client};
+
+/// Answers to the wizard questions, from a JSON file and/or flags
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Answers {
+    /// Hosted zone id or name
+    pub zone: Option<String>,
+    /// Creates the zone when it doesn't exist
+    pub create_zone: Option<bool>,
+    /// Record set name, or its prefix on the zone
+    pub record: Option<String>,
+    pub record_v6: Option<String>,
+    pub ipv4: Option<bool>,
+    pub ipv6: Option<bool>,
+    pub ttl: Option<i64>,
+    /// Provider names, in the order they are tried
+    #[serde(default, deserialize_with = "config::one_or_many")]
+    pub provider: Vec<String>,
+    #[serde(default, deserialize_with = "config::one_or_many")]
+    pub provider_v6: Vec<String>,
+    pub aws_access_key: Option<String>,
+    pub aws_secret_access_key: Option<String>,
+}
+
+impl Answers {
+    /// Reads the answers file, if any, and applies the flags over it
+    pub fn from_matches(
+        matches: &ArgMatches<'_>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut answers = match matches.value_of("answers") {
+            Some(path) => {
+                let file = std::fs::File::open(path)?;
+                serde_json::from_reader(file)
+                    .map_err(|err| format!("{}: {}", path, err))?
+            }
+            None => Answers::default(),
+        };
+
+        let value = |name| matches.value_of(name).map(String::from);
+        if let Some(zone) = value("zone") {
+            answers.zone = Some(zone);
+        }
+        if matches.is_present("create-zone") {
+            answers.create_zone = Some(true);
+        }
+        if let Some(record) = value("record") {
+            answers.record = Some(record);
+        }
+        if let Some(record) = value("record-v6") {
+            answers.record_v6 = Some(record);
+        }
+        if matches.is_present("no-ipv4") {
+            answers.ipv4 = Some(false);
+        }
+        if matches.is_present("ipv6") {
+            answers.ipv6 = Some(true);
+        }
+        if let Some(ttl) = value("ttl") {
+            answers.ttl = Some(ttl.parse().map_err(|_| "Invalid ttl")?);
+        }
+        let list = |names: String| -> Vec<String> {
+            names
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .collect()
+        };
+        if let Some(names) = value("provider") {
+            answers.provider = list(names);
+        }
+        if let Some(names) = value("provider-v6") {
+            answers.provider_v6 = list(names);
+        }
+        if let Some(access_key) = value("aws-access-key") {
+            answers.aws_access_key = Some(access_key);
+        }
+        if let Some(secret_key) = value("aws-secret-key") {
+            answers.aws_secret_access_key = Some(secret_key);
+        }
+
+        Ok(answers)
+    }
+}
+
+/// Returns the full record set name; names not ending with the zone name
+/// are taken as prefixes on it
+fn record_set_name(name: &str, zone_name: &str) -> String {
+    let name = name.trim_end_matches('.').to_lowercase();
+    let zone = zone_name.trim_end_matches('.').to_lowercase();
+
+    if name == zone || name.ends_with(&format!(".{}", zone)) {
+        format!("{}.", name)
+    } else {
+        format!("{}.{}.", name, zone)
+    }
+}
+
+/// Writes the configuration file from the answers, without prompting.
+/// The zone is checked against Route53 (and created if requested), like the
+/// wizard does.
+pub async fn init(
+    config_file: &str,
+    answers: Answers,
+    client: &Route53Client,
+    overwrite: bool,
+) -> Result<AppConfig, Box<dyn std::error::Error>> {
+    if !overwrite && std::path::Path::new(config_file).exists() {
+        return Err(format!(
+            "{} already exists, use --force to overwrite it",
+            config_file
+        )
+        .into());
+    }
+
+    let zone = answers.zone.as_deref().ok_or("zone is required")?;
+    let record = answers.record.as_deref().ok_or("record is required")?;
+
+    let hosted_zones = client
+        .list_hosted_zones()
+        .await
+        .ok_or("Error fetching Route53 hosted zones. Check your credentials")?;
+    let (zone_id, zone_name) =
+        match route53_client::find_hosted_zone(&hosted_zones, zone) {
+            Some(hosted_zone) => hosted_zone.clone(),
+            None if answers.create_zone.unwrap_or(false) => {
+                let (zone_id, zone_name, name_servers) =
+                    client.create_hosted_zone(zone).await?;
+                info!(
+                    zone = zone_id;
+                    "Created {}, delegate it to {}",
+                    zone_name,
+                    name_servers.join(", ")
+                );
+                (zone_id, zone_name)
+            }
+            None => {
+                return Err(format!("Hosted zone {} not found", zone).into())
+            }
+        };
+
+    let record_v6 = answers
+        .record_v6
+        .as_deref()
+        .map(|name| record_set_name(name, &zone_name));
+    let records = config::records_from(
+        Some(&record_set_name(record, &zone_name)),
+        record_v6.as_deref(),
+        answers.ipv4.unwrap_or(true),
+        answers.ipv6.unwrap_or(false),
+    );
+
+    let config = AppConfig {
+        version: config::CONFIG_VERSION,
+        zone_id,
+        records,
+        check_before_updating: Some(true),
+        ttl: Some(answers.ttl.unwrap_or(config::DEFAULT_TTL)),
+        providers: Some(config::ProvidersConfig {
+            ipv4: answers.provider,
+            ipv6: answers.provider_v6,
+        }),
+        aws_access_key: answers.aws_access_key,
+        aws_secret_access_key: answers.aws_secret_access_key,
+        ..Default::default()
+    };
+
+    let problems = config.validate();
+    if !problems.is_empty() {
+        return Err(problems.join("; ").into());
+    }
+
+    config.save(config_file)?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod init_tests {
+    use super::record_set_name;
+
+    #[test]
+    fn record_set_names() {
+        assert_eq!(
+            record_set_name("home", "example.com."),
+            "home.example.com."
+        );
+        assert_eq!(
+            record_set_name("Home.Example.com", "example.com."),
+            "home.example.com."
+        );
+        assert_eq!(
+            record_set_name("home.example.org", "example.com."),
+            "home.example.org.example.com."
+        );
+    }
+}
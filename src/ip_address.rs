@@ -1,8 +1,11 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::process::Command;
+use std::time::Duration;
+
+use crate::config::RecordSet;
 
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub enum MyIpProvider {
@@ -65,36 +68,55 @@ lazy_static! {
     ];
 }
 
-/// Returns true if provider is IPv6
-fn is_provider_v6(provider: &MyIpProvider) -> bool {
-    let provider_info = PROVIDERS_INFO
+fn provider_info(provider: &MyIpProvider) -> &'static ProviderInfo {
+    PROVIDERS_INFO
         .iter()
         .find(|&info| info.provider == *provider)
-        .expect("Unknown provider type");
-    provider_info.is_v6
+        .expect("Unknown provider type")
+}
+
+/// Returns the name used to identify the provider on metrics and reports
+pub fn provider_label(provider: &MyIpProvider) -> String {
+    let info = provider_info(provider);
+    if info.is_v6 {
+        format!("{}_v6", info.name)
+    } else {
+        String::from(info.name)
+    }
 }
 
-/// Returns an interator of providers
-/// The first item will be the specified provider, followed by alternative ones.
+/// Returns the provider with the name for the address family
+pub fn find_provider(name: &str, is_v6: bool) -> Option<MyIpProvider> {
+    PROVIDERS_INFO
+        .iter()
+        .find(|info| info.name == name && info.is_v6 == is_v6)
+        .map(|info| info.provider)
+}
+
+/// Returns the names of the providers of the address family
+pub fn provider_names(is_v6: bool) -> Vec<&'static str> {
+    PROVIDERS_INFO
+        .iter()
+        .filter(|info| info.is_v6 == is_v6)
+        .map(|info| info.name)
+        .collect()
+}
+
+/// Returns the providers to try, in order: the configured ones or, when
+/// there are none, every provider of the address family
 fn find_provider_with_alternatives(
-    provider: &MyIpProvider,
+    configured: &[MyIpProvider],
+    is_v6: bool,
 ) -> Vec<MyIpProvider> {
-    let is_v6 = is_provider_v6(provider);
+    if !configured.is_empty() {
+        return configured.to_vec();
+    }
 
-    let mut providers = vec![*provider];
-    let mut alternatives: Vec<MyIpProvider> = PROVIDERS_INFO
+    PROVIDERS_INFO
         .iter()
-        .filter_map(|candidate| {
-            if candidate.is_v6 == is_v6 && &candidate.provider != provider {
-                Some(candidate.provider)
-            } else {
-                None
-            }
-        })
-        .collect();
-
-    providers.append(&mut alternatives);
-    providers
+        .filter(|info| info.is_v6 == is_v6)
+        .map(|info| info.provider)
+        .collect()
 }
 
 async fn execute_ipify(v6: bool) -> Result<IpAddr, Box<dyn std::error::Error>> {
@@ -151,26 +173,149 @@ async fn execute_identme(
     }
 }
 
-/// Returns the current public ip address
-/// This function will try alternatives if the specified provider isn't available.
+/// Returns true for addresses on the shared address space of carrier-grade
+/// NAT (100.64.0.0/10)
+fn is_cgnat(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    octets[0] == 100 && octets[1] & 0xC0 == 64
+}
+
+/// Returns why the IPv4 address can't be reached from the internet, if so
+fn ipv4_rejection(ip: &Ipv4Addr) -> Option<&'static str> {
+    let octets = ip.octets();
+    if is_cgnat(ip) {
+        Some("carrier-grade NAT address")
+    } else if ip.is_private() {
+        Some("private address")
+    } else if ip.is_loopback() {
+        Some("loopback address")
+    } else if ip.is_link_local() {
+        Some("link-local address")
+    } else if ip.is_documentation() {
+        Some("documentation address")
+    } else if ip.is_multicast() {
+        Some("multicast address")
+    } else if octets[0] == 0
+        || octets[0] >= 240
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+        || (octets[0] == 198 && octets[1] & 0xFE == 18)
+    {
+        Some("reserved address")
+    } else {
+        None
+    }
+}
+
+/// Returns why the IPv6 address can't be reached from the internet, if so
+fn ipv6_rejection(ip: &Ipv6Addr) -> Option<&'static str> {
+    let segments = ip.segments();
+    if ip.is_loopback() || ip.is_unspecified() {
+        Some("loopback address")
+    } else if segments[0] & 0xFE00 == 0xFC00 {
+        Some("unique local address")
+    } else if segments[0] & 0xFFC0 == 0xFE80 {
+        Some("link-local address")
+    } else if ip.is_multicast() {
+        Some("multicast address")
+    } else if segments[0] == 0x2001 && segments[1] == 0x0DB8 {
+        Some("documentation address")
+    } else if segments[0] & 0xE000 != 0x2000 {
+        // Only 2000::/3 is allocated for global unicast
+        Some("reserved address")
+    } else {
+        None
+    }
+}
+
+/// Checks that a discovered address belongs to the requested family and can
+/// be reached from the internet, returning why it can't be published
+pub fn validate(ip: &IpAddr, is_v6: bool) -> Result<(), String> {
+    let rejection = match ip {
+        IpAddr::V4(_) if is_v6 => Some("IPv4 address for an AAAA record"),
+        IpAddr::V6(_) if !is_v6 => Some("IPv6 address for an A record"),
+        IpAddr::V4(ip) => ipv4_rejection(ip),
+        IpAddr::V6(ip) => ipv6_rejection(ip),
+    };
+
+    match rejection {
+        Some(reason) => Err(format!("{} is a {}", ip, reason)),
+        None => Ok(()),
+    }
+}
+
+/// Time a provider has to answer before the next one is asked
+pub const PROVIDER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Returns the current public ip address and the provider that returned it
+/// The providers are tried in order until one of them answers with an
+/// address that can be published.
 pub async fn current(
-    provider: &MyIpProvider,
-) -> Result<IpAddr, Box<dyn std::error::Error>> {
-    let providers_to_try = find_provider_with_alternatives(provider);
+    providers: &[MyIpProvider],
+    is_v6: bool,
+) -> Result<(IpAddr, MyIpProvider), Box<dyn std::error::Error>> {
+    let providers_to_try = find_provider_with_alternatives(providers, is_v6);
+    let mut answers = 0;
+    let mut cgnat_answers = 0;
     for provider in providers_to_try.iter() {
-        let res = match provider {
-            MyIpProvider::Ipify => execute_ipify(false).await,
-            MyIpProvider::IpifyV6 => execute_ipify(true).await,
-            MyIpProvider::Httpbin => execute_httpbin().await,
-            MyIpProvider::IdentMe => execute_identme(false).await,
-            MyIpProvider::IdentMeV6 => execute_identme(true).await,
+        let started_at = std::time::Instant::now();
+        let request = async {
+            match provider {
+                MyIpProvider::Ipify => execute_ipify(false).await,
+                MyIpProvider::IpifyV6 => execute_ipify(true).await,
+                MyIpProvider::Httpbin => execute_httpbin().await,
+                MyIpProvider::IdentMe => execute_identme(false).await,
+                MyIpProvider::IdentMeV6 => execute_identme(true).await,
+            }
+        };
+        let res = match tokio::time::timeout(PROVIDER_TIMEOUT, request).await {
+            Ok(res) => res,
+            Err(_) => {
+                Err(format!("no answer after {}s", PROVIDER_TIMEOUT.as_secs())
+                    .into())
+            }
         };
 
-        if res.is_ok() {
-            return res;
+        if let Ok(ip) = &res {
+            answers += 1;
+            if let IpAddr::V4(ipv4) = ip {
+                if is_cgnat(ipv4) {
+                    cgnat_answers += 1;
+                }
+            }
+        }
+        let res = match res {
+            Ok(ip) => match validate(&ip, is_v6) {
+                Ok(()) => Ok(ip),
+                Err(reason) => {
+                    warn!(
+                        provider = provider_label(provider);
+                        "Ignoring the address reported by the provider: {}",
+                        reason
+                    );
+                    Err(reason.into())
+                }
+            },
+            Err(err) => Err(err),
+        };
+        crate::metrics::provider_request(
+            &provider_label(provider),
+            res.is_ok(),
+            started_at.elapsed(),
+        );
+
+        if let Ok(ip) = res {
+            return Ok((ip, *provider));
         }
     }
 
+    if answers > 0 && cgnat_answers == answers {
+        warn!(
+            "Every provider reported an address in 100.64.0.0/10: this host \
+             is behind carrier-grade NAT and can't be reached on its own \
+             IPv4 address"
+        );
+    }
+
     let providers_tried: Vec<String> = providers_to_try
         .into_iter()
         .map(|p| format!("{:?}", p))
@@ -200,6 +345,107 @@ pub fn is_ipv6_available() -> bool {
     false
 }
 
+/// Parses the `ip -o addr` output, returning the global addresses along
+/// with their prefix lengths. Deprecated addresses are left out.
+fn parse_interface_addresses(output: &str) -> Vec<(IpAddr, u8)> {
+    let mut addresses = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let address = fields
+            .iter()
+            .position(|&field| field == "inet" || field == "inet6")
+            .and_then(|idx| fields.get(idx + 1));
+        let is_global = fields
+            .windows(2)
+            .any(|pair| pair[0] == "scope" && pair[1] == "global");
+        if !is_global || fields.contains(&"deprecated") {
+            continue;
+        }
+
+        let mut parts = address.unwrap_or(&"").splitn(2, '/');
+        let ip = parts.next().and_then(|ip| ip.parse().ok());
+        let length = parts.next().and_then(|length| length.parse().ok());
+        if let (Some(ip), Some(length)) = (ip, length) {
+            addresses.push((ip, length));
+        }
+    }
+    addresses
+}
+
+/// Returns the global addresses of the network interface (with their prefix
+/// lengths), in the order `ip addr` lists them
+pub fn interface_addresses(
+    interface: &str,
+) -> Result<Vec<(IpAddr, u8)>, Box<dyn std::error::Error>> {
+    let ip_out = Command::new("ip")
+        .args(["-o", "addr", "show", "dev", interface])
+        .output()?;
+    if !ip_out.status.success() {
+        let ex = IpAddressResolutionError {
+            message: format!(
+                "interface {}: {}",
+                interface,
+                String::from_utf8_lossy(&ip_out.stderr).trim()
+            ),
+        };
+        return Err(Box::new(ex));
+    }
+
+    Ok(parse_interface_addresses(&String::from_utf8_lossy(
+        &ip_out.stdout,
+    )))
+}
+
+/// Returns the first address of the family on the interface. With
+/// `public_only` it must be one that can be published on a public zone.
+pub fn interface_address(
+    interface: &str,
+    is_v6: bool,
+    public_only: bool,
+) -> Result<(IpAddr, u8), Box<dyn std::error::Error>> {
+    let found = interface_addresses(interface)?.into_iter().find(|(ip, _)| {
+        if public_only {
+            validate(ip, is_v6).is_ok()
+        } else {
+            ip.is_ipv6() == is_v6
+        }
+    });
+
+    match found {
+        Some(address) => Ok(address),
+        None => {
+            let ex = IpAddressResolutionError {
+                message: format!(
+                    "interface {} has no {}{} address",
+                    interface,
+                    if public_only { "public " } else { "" },
+                    if is_v6 { "IPv6" } else { "IPv4" }
+                ),
+            };
+            Err(Box::new(ex))
+        }
+    }
+}
+
+/// Returns the address to publish on the record set and where it came from:
+/// its interface or the provider that reported it
+pub async fn record_set_address(
+    record_set: &RecordSet<'_>,
+) -> Result<(IpAddr, String), Box<dyn std::error::Error>> {
+    match record_set.interface {
+        Some(interface) => {
+            let (ip, _) =
+                interface_address(interface, record_set.is_v6, false)?;
+            Ok((ip, format!("interface {}", interface)))
+        }
+        None => {
+            let (ip, provider) =
+                current(&record_set.providers, record_set.is_v6).await?;
+            Ok((ip, provider_label(&provider)))
+        }
+    }
+}
+
 #[cfg(test)]
 mod ip_tests {
     #[tokio::test]
@@ -228,16 +474,80 @@ mod ip_tests {
 
     #[test]
     fn provider_alternatives() {
-        use super::{find_provider_with_alternatives, MyIpProvider};
+        use super::{
+            find_provider, find_provider_with_alternatives, MyIpProvider,
+        };
+
+        let configured = [MyIpProvider::Httpbin, MyIpProvider::Ipify];
+        let alts = find_provider_with_alternatives(&configured, false);
+        assert_eq!(alts, configured);
 
-        let alts = find_provider_with_alternatives(&MyIpProvider::Httpbin);
-        assert_eq!(MyIpProvider::Httpbin, alts[0]);
-        assert!(alts.contains(&MyIpProvider::Ipify));
-        assert!(!alts.contains(&MyIpProvider::IpifyV6));
+        let alts = find_provider_with_alternatives(&[], true);
+        assert_eq!(alts, vec![MyIpProvider::IpifyV6, MyIpProvider::IdentMeV6]);
+
+        assert_eq!(
+            find_provider("identme", true),
+            Some(MyIpProvider::IdentMeV6)
+        );
+        assert_eq!(find_provider("httpbin", true), None);
+        assert_eq!(find_provider("ipfy", false), None);
+    }
 
-        let alts = find_provider_with_alternatives(&MyIpProvider::IdentMeV6);
-        assert_eq!(MyIpProvider::IdentMeV6, alts[0]);
-        assert!(alts.contains(&MyIpProvider::IpifyV6));
-        assert!(!alts.contains(&MyIpProvider::IdentMe));
+    #[test]
+    fn address_validation() {
+        use super::validate;
+
+        let valid = |ip: &str, is_v6| validate(&ip.parse().unwrap(), is_v6);
+
+        assert!(valid("93.184.216.34", false).is_ok());
+        assert!(valid("2606:2800:220:1::1", true).is_ok());
+        for ip in [
+            "10.1.2.3",
+            "100.64.0.1",
+            "100.127.255.254",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "192.0.2.1",
+            "198.18.0.1",
+            "0.1.2.3",
+            "240.0.0.1",
+            "255.255.255.255",
+        ]
+        .iter()
+        {
+            assert!(valid(ip, false).is_err(), "{} accepted", ip);
+        }
+        assert!(valid("100.128.0.1", false).is_ok());
+
+        for ip in ["::1", "fd00::1", "fe80::1", "2001:db8::1", "::ffff:1.2.3.4"]
+            .iter()
+        {
+            assert!(valid(ip, true).is_err(), "{} accepted", ip);
+        }
+
+        // Family mismatches
+        assert!(valid("93.184.216.34", true).is_err());
+        assert!(valid("2606:2800:220:1::1", false).is_err());
+    }
+
+    #[test]
+    fn interface_addresses() {
+        let output = "\
+            1: lo    inet 127.0.0.1/8 scope host lo\\       valid_lft forever\n\
+            4: eth0    inet 203.0.113.7/24 brd 203.0.113.255 scope global eth0\n\
+            4: eth0    inet6 2001:db8:1:2::7/64 scope global deprecated dynamic\n\
+            4: eth0    inet6 2001:db8:5:6::7/56 scope global dynamic mngtmpaddr\n\
+            4: eth0    inet6 fe80::1/64 scope link\n";
+
+        let addresses = super::parse_interface_addresses(output);
+        assert_eq!(
+            addresses,
+            vec![
+                ("203.0.113.7".parse().unwrap(), 24),
+                ("2001:db8:5:6::7".parse().unwrap(), 56)
+            ]
+        );
     }
 }
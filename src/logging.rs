@@ -0,0 +1,352 @@
+use std::fmt;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+
+use chrono::{SecondsFormat, Utc};
+
+/// Identifier used on syslog and journald entries
+const IDENTIFIER: &str = "rust-aws-ddns";
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Syslog facility used on all messages (daemon)
+const SYSLOG_FACILITY: u8 = 3;
+
+/// Structured data ID for syslog (private enterprise number for
+/// documentation, see RFC 5612)
+const SYSLOG_SD_ID: &str = "ddns@32473";
+
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// Returns the level matching the number of `-v` and `-q` flags
+    pub fn from_verbosity(verbose: u64, quiet: bool) -> Self {
+        if quiet {
+            return Level::Error;
+        }
+
+        match verbose {
+            0 => Level::Info,
+            1 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    /// Syslog severity, also used as the journald priority
+    fn severity(self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        f.pad(s)
+    }
+}
+
+/// Where log messages are written to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Target {
+    /// Human readable lines on stdout
+    Text,
+    /// One JSON object per line on stdout
+    Json,
+    /// RFC 5424 messages on the local syslog socket
+    Syslog,
+    /// Native journald protocol
+    Journald,
+}
+
+impl Target {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Target::Text),
+            "json" => Some(Target::Json),
+            "syslog" => Some(Target::Syslog),
+            "journald" => Some(Target::Journald),
+            _ => None,
+        }
+    }
+}
+
+struct Logger {
+    level: Level,
+    target: Target,
+    socket: Option<UnixDatagram>,
+    use_stderr: bool,
+}
+
+lazy_static! {
+    static ref LOGGER: Mutex<Logger> = Mutex::new(Logger {
+        level: Level::Info,
+        target: Target::Text,
+        socket: None,
+        use_stderr: false,
+    });
+}
+
+/// Configures the level and target of the log messages. With `use_stderr`
+/// the text and JSON lines are written to stderr instead of stdout.
+pub fn init(
+    level: Level,
+    target: Target,
+    use_stderr: bool,
+) -> std::io::Result<()> {
+    let socket = match target {
+        Target::Syslog => Some(connect(SYSLOG_SOCKET)?),
+        Target::Journald => Some(connect(JOURNALD_SOCKET)?),
+        _ => None,
+    };
+
+    let mut logger = LOGGER.lock().unwrap();
+    logger.level = level;
+    logger.target = target;
+    logger.socket = socket;
+    logger.use_stderr = use_stderr;
+
+    Ok(())
+}
+
+fn connect(path: &str) -> std::io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+/// Logs a message with its context fields. Use the `error!`, `warn!`,
+/// `info!` and `debug!` macros instead of calling this directly.
+pub fn log(level: Level, fields: &[(&str, String)], message: &str) {
+    let logger = LOGGER.lock().unwrap();
+    if level > logger.level {
+        return;
+    }
+
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let mut out: Box<dyn Write> = if logger.use_stderr {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    };
+    let res = match (logger.target, &logger.socket) {
+        (Target::Syslog, Some(socket)) => socket
+            .send(format_syslog(&timestamp, level, fields, message).as_bytes())
+            .map(|_| ()),
+        (Target::Journald, Some(socket)) => socket
+            .send(&format_journald(level, fields, message))
+            .map(|_| ()),
+        (Target::Json, _) => {
+            writeln!(out, "{}", format_json(&timestamp, level, fields, message))
+        }
+        _ => {
+            writeln!(out, "{}", format_text(&timestamp, level, fields, message))
+        }
+    };
+
+    // Falling back to stderr if the log socket stopped working
+    if let Err(err) = res {
+        eprintln!("{} (logging failed: {})", message, err);
+    }
+}
+
+fn format_text(
+    timestamp: &str,
+    level: Level,
+    fields: &[(&str, String)],
+    message: &str,
+) -> String {
+    let mut line = format!("{} {:<5} {}", timestamp, level, message);
+    for (key, value) in fields.iter() {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+    line
+}
+
+fn format_json(
+    timestamp: &str,
+    level: Level,
+    fields: &[(&str, String)],
+    message: &str,
+) -> String {
+    let mut entry = serde_json::Map::new();
+    entry.insert("timestamp".into(), timestamp.into());
+    entry.insert("level".into(), level.to_string().to_lowercase().into());
+    entry.insert("message".into(), message.into());
+    for (key, value) in fields.iter() {
+        entry.insert(key.to_string(), value.as_str().into());
+    }
+
+    serde_json::Value::Object(entry).to_string()
+}
+
+fn format_syslog(
+    timestamp: &str,
+    level: Level,
+    fields: &[(&str, String)],
+    message: &str,
+) -> String {
+    let priority = SYSLOG_FACILITY * 8 + level.severity();
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|_| String::from("-"));
+
+    let structured_data = if fields.is_empty() {
+        String::from("-")
+    } else {
+        let params: Vec<String> = fields
+            .iter()
+            .map(|(key, value)| {
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace(']', "\\]");
+                format!("{}=\"{}\"", key, value)
+            })
+            .collect();
+        format!("[{} {}]", SYSLOG_SD_ID, params.join(" "))
+    };
+
+    format!(
+        "<{}>1 {} {} {} {} - {} {}",
+        priority,
+        timestamp,
+        hostname,
+        IDENTIFIER,
+        std::process::id(),
+        structured_data,
+        message
+    )
+}
+
+fn format_journald(
+    level: Level,
+    fields: &[(&str, String)],
+    message: &str,
+) -> Vec<u8> {
+    let mut entry = Vec::new();
+    let mut append = |key: &str, value: &str| {
+        if value.contains('\n') {
+            // Values with newlines are sent with an explicit length
+            entry.extend_from_slice(key.as_bytes());
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            entry.extend_from_slice(value.as_bytes());
+            entry.push(b'\n');
+        } else {
+            entry.extend_from_slice(format!("{}={}\n", key, value).as_bytes());
+        }
+    };
+
+    append("MESSAGE", message);
+    append("PRIORITY", &level.severity().to_string());
+    append("SYSLOG_IDENTIFIER", IDENTIFIER);
+    for (key, value) in fields.iter() {
+        append(&key.to_uppercase(), value);
+    }
+
+    entry
+}
+
+/// Logs a message on the given level, optionally preceded by context fields:
+/// `log_event!(Level::Info, record = name, ip = ip; "Updated {}", name)`
+macro_rules! log_event {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        $crate::logging::log(
+            $level,
+            &[$((stringify!($key), $value.to_string())),+],
+            &format!($($arg)+),
+        )
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::logging::log($level, &[], &format!($($arg)+))
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log_event!($crate::logging::Level::Error, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log_event!($crate::logging::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log_event!($crate::logging::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log_event!($crate::logging::Level::Debug, $($arg)+) };
+}
+
+#[cfg(test)]
+mod logging_tests {
+    use super::*;
+
+    fn fields() -> Vec<(&'static str, String)> {
+        vec![
+            ("record", String::from("home.example.com.")),
+            ("ip", String::from("192.0.2.1")),
+        ]
+    }
+
+    #[test]
+    fn text_and_json() {
+        let ts = "2020-01-01T00:00:00.000Z";
+        assert_eq!(
+            format_text(ts, Level::Info, &fields(), "Updated"),
+            "2020-01-01T00:00:00.000Z INFO  Updated \
+             record=home.example.com. ip=192.0.2.1"
+        );
+
+        let json = format_json(ts, Level::Warn, &fields(), "Updated");
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["level"], "warn");
+        assert_eq!(value["record"], "home.example.com.");
+        assert_eq!(value["ip"], "192.0.2.1");
+    }
+
+    #[test]
+    fn syslog() {
+        let ts = "2020-01-01T00:00:00.000Z";
+        let line = format_syslog(ts, Level::Error, &fields(), "Failed");
+        assert!(line.starts_with("<27>1 2020-01-01T00:00:00.000Z "));
+        assert!(line.ends_with(
+            " - [ddns@32473 record=\"home.example.com.\" ip=\"192.0.2.1\"] \
+             Failed"
+        ));
+    }
+
+    #[test]
+    fn journald() {
+        let entry = format_journald(Level::Info, &fields(), "Updated");
+        let entry = String::from_utf8(entry).unwrap();
+        assert_eq!(
+            entry,
+            "MESSAGE=Updated\nPRIORITY=6\nSYSLOG_IDENTIFIER=rust-aws-ddns\n\
+             RECORD=home.example.com.\nIP=192.0.2.1\n"
+        );
+
+        let entry = format_journald(Level::Info, &[], "two\nlines");
+        assert!(entry.starts_with(b"MESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n"));
+    }
+}
@@ -1,108 +1,764 @@
-use clap::App;
-use std::net::{IpAddr, ToSocketAddrs};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+#[macro_use]
+mod logging;
 
 mod aws_credentials;
+mod check;
+mod commands;
 
 mod ip_address;
-use ip_address::MyIpProvider;
 
 mod config;
+use config::RecordSet;
+mod dns;
+mod drift;
+mod hooks;
+mod iam;
+mod init;
+mod metrics;
+mod netlink;
+mod notifications;
+mod prefix;
+mod report;
+use report::{OutputFormat, RecordReport};
+
 mod route53_client;
+use route53_client::RecordSetChange;
+
+mod state;
+
+mod systemd;
 
 #[macro_use]
 extern crate lazy_static;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-static mut QUIET_MODE: bool = false;
-fn println(message: &str) {
-    unsafe {
-        if !QUIET_MODE {
-            println!("{}", message);
-        }
-    }
+/// Arguments of the `run` subcommand, also accepted without a subcommand
+fn run_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::from_usage(
+            "-d, --daemon 'Keeps running, checking the IP addresses periodically'",
+        ),
+        Arg::from_usage(
+            "--interval=[SECONDS] 'Seconds between checks in daemon mode (default: 300, or 3600 when watching address changes)'",
+        ),
+        Arg::from_usage(
+            "--no-netlink 'Only checks periodically in daemon mode, without watching address and route changes'",
+        ),
+        Arg::from_usage(
+            "--metrics-listen=[ADDR] 'Serves Prometheus metrics on ADDR in daemon mode'",
+        ),
+        Arg::from_usage(
+            "--adopt 'Takes over existing record sets without a matching ownership marker'",
+        ),
+    ]
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let global_args = [
+        Arg::from_usage("-c, --config=[FILE] 'Sets a custom config file instead of the search path'"),
+        Arg::from_usage("--csv=[FILE] 'Sets a custom credentials file'"),
+        Arg::from_usage("-q 'Quiet mode, only errors are logged'"),
+        Arg::from_usage("-v... 'Verbose mode (-vv for even more)'"),
+        Arg::from_usage(
+            "--log=[TARGET] 'Log output: text, json, syslog or journald (default: text)'",
+        ),
+        Arg::from_usage(
+            "--output=[FORMAT] 'Output of run and status: text or json (default: text)'",
+        ),
+    ];
+
     let clap_matches = App::new("rust-aws-ddns")
         .version(VERSION)
         .author("Alessandro Menezes <alessandroasm@gmail.com>")
         .about("This application implements DDNS backed by AWS Route 53")
-        .args_from_usage(
-            "-c, --config=[FILE] 'Sets a custom config file'
-            --csv=[FILE]         'Sets a custom credentials file'
-            -q                   'Quiet mode'",
+        .args(&global_args.map(|arg| arg.global(true)))
+        .args(&run_args())
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Updates the record sets (default)")
+                .args(&run_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Shows the detected IP addresses and the published ones"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Monitoring plugin: checks that the published addresses are current"),
+        )
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("Publishes an address known beforehand, e.g. from PPP or DHCP hooks")
+                .args_from_usage(
+                    "--ip=<ADDR>             'Address to publish, or - to read it from stdin'
+                    --record=[NAME]         'Record set to update (default: every one of the address family)'
+                    --adopt                 'Takes over existing record sets without a matching ownership marker'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list-zones")
+                .about("Lists the Route53 hosted zones"),
+        )
+        .subcommand(
+            SubCommand::with_name("list-records")
+                .about("Lists the record sets of a hosted zone")
+                .arg(Arg::from_usage("<ZONE> 'Hosted zone id or name'")),
+        )
+        .subcommand(
+            SubCommand::with_name("configure")
+                .about("Runs the configuration wizard again"),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Writes the configuration file without prompting")
+                .args_from_usage(
+                    "--answers=[FILE]        'JSON file with the answers; flags override it'
+                    --zone=[ZONE]           'Hosted zone id or name'
+                    --create-zone           'Creates the hosted zone if it does not exist'
+                    --record=[NAME]         'Record set name or prefix on the zone'
+                    --record-v6=[NAME]      'IPv6 record set name or prefix (default: same as --record)'
+                    --no-ipv4               'Does not update the IPv4 address'
+                    --ipv6                  'Updates the IPv6 address'
+                    --ttl=[SECONDS]         'TTL of the record sets (default: 120)'
+                    --provider=[NAMES]      'IPv4 address providers, tried in order: ipify, httpbin, identme'
+                    --provider-v6=[NAMES]   'IPv6 address providers, tried in order: ipify, identme'
+                    --aws-access-key=[KEY]  'AWS access key stored on the configuration'
+                    --aws-secret-key=[KEY]  'AWS secret key stored on the configuration'
+                    --force                 'Overwrites an existing configuration file'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Checks the configuration file without contacting AWS"),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Manages the configuration file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("migrate").about(
+                    "Rewrites the configuration file on the current schema, keeping a backup",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("iam-policy")
+                .about("Prints a least-privilege IAM policy for the configured record sets")
+                .arg(Arg::from_usage(
+                    "--wizard 'Also allows the calls made by configure and list-zones'",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("install-systemd")
+                .about("Writes systemd unit files for the current config")
+                .args_from_usage(
+                    "--dir=[DIR]             'Directory for the unit files (default: /etc/systemd/system)'
+                    -d, --daemon            'Installs a long-running service instead of a timer'
+                    --interval=[SECONDS]    'Seconds between checks (default: 300)'",
+                ),
         )
         .get_matches();
 
-    // Load configuration
+    // Set up logging
     let quiet_mode = clap_matches.is_present("q");
-    unsafe {
-        QUIET_MODE = quiet_mode;
+    let log_level = logging::Level::from_verbosity(
+        clap_matches.occurrences_of("v"),
+        quiet_mode,
+    );
+    let log_target = clap_matches.value_of("log").unwrap_or("text");
+    let log_target =
+        logging::Target::parse(log_target).expect("Invalid log output");
+    let output = clap_matches.value_of("output").unwrap_or("text");
+    let output = OutputFormat::parse(output).expect("Invalid output format");
+
+    // The results are written on stdout, so logs go to stderr
+    let log_to_stderr = output == OutputFormat::Json;
+    logging::init(log_level, log_target, log_to_stderr)?;
+
+    // Without a config file the search path is used
+    let config_file = clap_matches.value_of("config");
+    let credentials_file = clap_matches.value_of("csv");
+
+    // Subcommands that don't need AWS access
+    match clap_matches.subcommand() {
+        ("install-systemd", Some(matches)) => {
+            let unit_dir =
+                matches.value_of("dir").unwrap_or("/etc/systemd/system");
+            let interval = matches
+                .value_of("interval")
+                .map(|s| s.parse::<u64>().expect("Invalid interval"))
+                .unwrap_or(300);
+
+            // Hooks run commands that change the system (firewall, wg,
+            // systemctl...), which the sandbox would prevent
+            let has_hooks = config::AppConfig::load(config_file)
+                .map(|config| {
+                    config.on_change.is_some() || config.on_failure.is_some()
+                })
+                .unwrap_or(false);
+            if has_hooks {
+                warn!(
+                    "on_change / on_failure hooks are configured: the service \
+                     is written without sandboxing so that they keep working"
+                );
+            }
+
+            let written = systemd::install_units(
+                unit_dir,
+                &config::target_file(config_file),
+                credentials_file,
+                matches.is_present("daemon"),
+                interval,
+                !has_hooks,
+            )?;
+            for path in written.iter() {
+                info!("Wrote {}", path.display());
+            }
+            return Ok(());
+        }
+        ("validate", _) => return commands::validate(config_file),
+        ("config", Some(matches)) => {
+            if let ("migrate", _) = matches.subcommand() {
+                let config_file = config::target_file(config_file);
+                return commands::migrate_config(&config_file);
+            }
+            return Ok(());
+        }
+        ("init", Some(matches)) => {
+            let answers = init::Answers::from_matches(matches)?;
+            let keys = config::AppConfig {
+                aws_access_key: answers.aws_access_key.clone(),
+                aws_secret_access_key: answers.aws_secret_access_key.clone(),
+                ..Default::default()
+            };
+            let client = new_route53_client(credentials_file, Some(&keys));
+
+            let config_file = config::target_file(config_file);
+            let force = matches.is_present("force");
+            init::init(&config_file, answers, &client, force).await?;
+            info!("Wrote {}", config_file);
+            return Ok(());
+        }
+        ("iam-policy", Some(matches)) => {
+            let app_config = config::AppConfig::load(config_file)?;
+            let policy = iam::policy(&app_config, matches.is_present("wizard"));
+            println!("{}", serde_json::to_string_pretty(&policy)?);
+            return Ok(());
+        }
+        ("configure", _) => {
+            config::AppConfig::configure(config_file).await;
+            return Ok(());
+        }
+        ("check", _) => {
+            let app_config = match config::AppConfig::load(config_file) {
+                Ok(app_config) => app_config,
+                Err(err) => {
+                    println!("DDNS UNKNOWN - {}", err);
+                    std::process::exit(check::State::Unknown as i32);
+                }
+            };
+            let client =
+                new_route53_client(credentials_file, Some(&app_config));
+
+            let state = check::check(&app_config, &client).await;
+            std::process::exit(state as i32);
+        }
+        ("list-zones", _) => {
+            let app_config = config::AppConfig::load(config_file).ok();
+            let client =
+                new_route53_client(credentials_file, app_config.as_ref());
+            return commands::list_zones(&client).await;
+        }
+        ("list-records", Some(matches)) => {
+            let app_config = config::AppConfig::load(config_file).ok();
+            let client =
+                new_route53_client(credentials_file, app_config.as_ref());
+            let zone = matches.value_of("ZONE").unwrap();
+            return commands::list_records(&client, zone).await;
+        }
+        _ => {}
     }
 
-    let config_file = clap_matches
-        .value_of("config")
-        .unwrap_or("rust-aws-ddns.yml");
-    let app_config = config::AppConfig::parse(config_file, quiet_mode);
-    let app_config = app_config.await.unwrap();
+    // Load configuration
+    let app_config = config::AppConfig::parse(config_file, quiet_mode)
+        .await
+        .ok_or("No configuration")?;
+    let adopt = match clap_matches.subcommand() {
+        ("set", Some(matches)) | ("run", Some(matches)) => {
+            matches.is_present("adopt")
+        }
+        _ => clap_matches.is_present("adopt"),
+    };
+    let route53_client =
+        new_route53_client(credentials_file, Some(&app_config))
+            .with_ownership(app_config.owner_id(), adopt);
+
+    let res = match clap_matches.subcommand() {
+        ("status", _) => {
+            commands::status(&app_config, &route53_client, output).await
+        }
+        ("set", Some(matches)) => {
+            set(&app_config, &route53_client, matches, output).await
+        }
+        ("run", Some(matches)) => {
+            run(&app_config, &route53_client, matches, output).await
+        }
+        _ => run(&app_config, &route53_client, &clap_matches, output).await,
+    };
+
+    // Webhooks are delivered in the background
+    notifications::flush().await;
+    res
+}
 
+/// Creates the Route53 client, using the credentials from the CSV file or
+/// the configuration file. Without them the env / system credentials are used.
+fn new_route53_client(
+    credentials_file: Option<&str>,
+    app_config: Option<&config::AppConfig>,
+) -> route53_client::Route53Client {
     // Get API credentials
-    let credentials_file = clap_matches
-        .value_of("csv")
-        .unwrap_or("aws_user_credentials.csv");
+    let credentials_file =
+        credentials_file.unwrap_or("aws_user_credentials.csv");
     let mut credentials = aws_credentials::from_csv(credentials_file);
 
-    if credentials.is_none()
-        && app_config.aws_access_key.is_some()
-        && app_config.aws_secret_access_key.is_some()
-    {
-        let access_key = app_config.aws_access_key.as_ref().unwrap();
-        let secret_access_key =
-            app_config.aws_secret_access_key.as_ref().unwrap();
+    if let Some(app_config) = app_config {
+        if let (None, Some(access_key), Some(secret_access_key)) = (
+            &credentials,
+            &app_config.aws_access_key,
+            &app_config.aws_secret_access_key,
+        ) {
+            credentials = Some(aws_credentials::AppAwsCredentials {
+                access_key: String::from(access_key),
+                secret_access_key: String::from(secret_access_key),
+            });
+        }
+    }
+
+    route53_client::Route53Client::new(credentials)
+}
+
+/// Checks and updates the IPs, once or periodically in daemon mode
+async fn run(
+    app_config: &config::AppConfig,
+    route53_client: &route53_client::Route53Client,
+    matches: &ArgMatches<'_>,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !matches.is_present("daemon") {
+        let reports =
+            update_all(app_config, route53_client, netlink::Families::ALL)
+                .await;
+        if output == OutputFormat::Json {
+            report::print_json(&reports);
+        }
+
+        let failures = reports.iter().filter(|r| r.error.is_some()).count();
+        if failures > 0 {
+            return Err(format!("{} record set(s) failed", failures).into());
+        }
+        return Ok(());
+    }
+
+    // Daemon mode. Address and route changes trigger an update right away,
+    // leaving the periodic check as a safety net.
+    let mut events = if matches.is_present("no-netlink") {
+        None
+    } else {
+        match netlink::subscribe() {
+            Ok(events) => Some(events),
+            Err(err) => {
+                warn!("Not watching address changes: {}", err);
+                None
+            }
+        }
+    };
+    let interval = matches
+        .value_of("interval")
+        .map(|s| s.parse::<u64>().expect("Invalid interval"))
+        .unwrap_or(if events.is_some() { 3600 } else { 300 });
+    let interval = Duration::from_secs(interval);
 
-        credentials = Some(aws_credentials::AppAwsCredentials {
-            access_key: String::from(access_key),
-            secret_access_key: String::from(secret_access_key),
+    if let Some(addr) = matches.value_of("metrics-listen") {
+        let addr: SocketAddr = addr.parse().expect("Invalid metrics address");
+        let server = metrics::serve(addr).map_err(|err| {
+            format!("Failed to serve the metrics on {}: {}", addr, err)
+        })?;
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("Metrics endpoint failed: {}", err);
+            }
         });
     }
 
-    // Checking and updating IPs
-    let route53_client = route53_client::Route53Client::new(credentials);
+    systemd::notify("READY=1");
+    let mut families = netlink::Families::ALL;
+    let drift_interval = Duration::from_secs(app_config.drift_interval());
+    let mut last_drift_check: Option<std::time::Instant> = None;
+    loop {
+        // Errors are reported and retried on the next iteration
+        let reports = update_all(app_config, route53_client, families).await;
+        if app_config.checks_drift()
+            && last_drift_check
+                .iter()
+                .all(|last| last.elapsed() >= drift_interval)
+        {
+            drift::check_all(app_config, route53_client).await;
+            last_drift_check = Some(std::time::Instant::now());
+        }
+        if output == OutputFormat::Json {
+            report::print_json(&reports);
+        }
+
+        let status: Vec<String> = reports
+            .iter()
+            .map(|report| match (&report.error, &report.detected_ip) {
+                (Some(err), _) => format!("{} failed: {}", report.name, err),
+                (None, Some(ip)) => format!("{} {}", report.name, ip),
+                (None, None) => report.name.clone(),
+            })
+            .collect();
+        systemd::notify(&format!("STATUS={}", status.join(", ")));
+
+        families = match events.as_mut() {
+            Some(receiver) => {
+                match wait_for_changes(receiver, interval).await {
+                    Some(families) => families,
+                    None => {
+                        warn!("Stopped watching address changes");
+                        events = None;
+                        netlink::Families::ALL
+                    }
+                }
+            }
+            None => {
+                systemd::sleep(interval).await;
+                netlink::Families::ALL
+            }
+        };
+    }
+}
+
+/// Waits for the families affected by address or route changes, once they
+/// settle down, or for the interval to elapse (every family is checked
+/// then). Returns None if the events stopped.
+async fn wait_for_changes(
+    receiver: &mut tokio::sync::mpsc::UnboundedReceiver<netlink::Families>,
+    interval: Duration,
+) -> Option<netlink::Families> {
+    let mut families = tokio::select! {
+        _ = systemd::sleep(interval) => return Some(netlink::Families::ALL),
+        families = receiver.recv() => families?,
+    };
+
+    // Changes come in bursts (address, then route...), so we wait for them
+    // to stop before checking
+    while let Ok(more) =
+        tokio::time::timeout(netlink::DEBOUNCE, receiver.recv()).await
+    {
+        families.add(more?);
+        systemd::feed_watchdog();
+    }
+
+    debug!(
+        ipv4 = families.ipv4, ipv6 = families.ipv6;
+        "Address or route change detected"
+    );
+    Some(families)
+}
+
+/// Publishes the address given on the command line (or stdin) on the record
+/// sets of its family, without asking the providers
+async fn set(
+    app_config: &config::AppConfig,
+    route53_client: &route53_client::Route53Client,
+    matches: &ArgMatches<'_>,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ip = match matches.value_of("ip").unwrap() {
+        "-" => {
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input)?;
+            input
+        }
+        ip => ip.to_string(),
+    };
+    let ip: IpAddr = ip
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid address \"{}\"", ip.trim()))?;
+    let is_v6 = ip.is_ipv6();
+
+    // Records taking their address from an interface don't get this one
+    let record = matches.value_of("record");
+    let record_sets: Vec<RecordSet> = app_config
+        .record_sets()
+        .into_iter()
+        .filter(|record_set| {
+            record_set.is_v6 == is_v6
+                && record_set.interface.is_none()
+                && record.iter().all(|record| {
+                    route53_client::is_same_name(record_set.name, record)
+                })
+        })
+        .collect();
+    if record_sets.is_empty() {
+        let family = if is_v6 { "AAAA" } else { "A" };
+        return Err(match record {
+            Some(record) => format!("no {} record set \"{}\"", family, record),
+            None => format!("no {} record sets are configured", family),
+        }
+        .into());
+    }
+    ip_address::validate(&ip, is_v6)?;
 
-    // IPv4 First
-    let provider_v4 = app_config.get_provider();
-    if app_config.update_ipv4 {
-        let record_set = app_config.record_set.as_ref();
-        update_record_set(
-            &app_config,
-            &route53_client,
-            &provider_v4,
+    // The address is known to be right, so it doesn't wait to be stable
+    let state_file = app_config.state_file();
+    let mut state = load_state(app_config, &state_file);
+    let mut reports = Vec::new();
+    for record_set in record_sets.iter() {
+        let mut report =
+            RecordReport::new(record_set.name, record_set.zone_id, is_v6);
+        report.provider = Some(String::from("set"));
+        let res = publish_address(
+            app_config,
+            route53_client,
             record_set,
+            ip,
+            None,
+            state.record(record_set),
+            &mut report,
         )
-        .await?;
+        .await;
+
+        if let Err(err) = res {
+            error!(record = record_set.name; "{}", err);
+            report.error = Some(err.to_string());
+
+            let failures = state.record(record_set).failed();
+            notifications::update_failed(
+                app_config,
+                record_set,
+                failures,
+                err.as_ref(),
+            );
+            hooks::update_failed(app_config, record_set, err.as_ref()).await;
+        }
+        reports.push(report);
+    }
+    save_state(app_config, &state_file, &state);
+    if output == OutputFormat::Json {
+        report::print_json(&reports);
     }
 
-    // And then IPv6
-    if app_config.update_ipv6 && ip_address::is_ipv6_available() {
-        let provider_v6 = if provider_v4 == MyIpProvider::IdentMe {
-            MyIpProvider::IdentMeV6
-        } else {
-            MyIpProvider::IpifyV6
-        };
-        let record_set: &str = app_config
-            .record_set_v6
-            .as_ref()
-            .unwrap_or(&app_config.record_set);
-
-        update_record_set(
-            &app_config,
-            &route53_client,
-            &provider_v6,
-            record_set,
+    let failures = reports.iter().filter(|r| r.error.is_some()).count();
+    if failures > 0 {
+        return Err(format!("{} record set(s) failed", failures).into());
+    }
+    Ok(())
+}
+
+/// Updates the configured record sets, reporting the outcome of each one
+async fn update_all(
+    app_config: &config::AppConfig,
+    route53_client: &route53_client::Route53Client,
+    families: netlink::Families,
+) -> Vec<RecordReport> {
+    let state_file = app_config.state_file();
+    let mut state = load_state(app_config, &state_file);
+    let mut reports = Vec::new();
+
+    // IPv4 first, and then IPv6
+    for record_set in app_config.record_sets() {
+        let is_v6 = record_set.is_v6;
+        if !families.contains(is_v6) {
+            continue;
+        }
+        if is_v6
+            && record_set.interface.is_none()
+            && !ip_address::is_ipv6_available()
+        {
+            continue;
+        }
+
+        let mut report =
+            RecordReport::new(record_set.name, record_set.zone_id, is_v6);
+        let res = update_record_set(
+            app_config,
+            route53_client,
+            &record_set,
+            state.record(&record_set),
+            &mut report,
         )
+        .await;
+
+        // Errors on a record set don't prevent updating the others
+        if let Err(err) = res {
+            error!(record = record_set.name; "{}", err);
+            report.error = Some(err.to_string());
+
+            let failures = state.record(&record_set).failed();
+            notifications::update_failed(
+                app_config,
+                &record_set,
+                failures,
+                err.as_ref(),
+            );
+            hooks::update_failed(app_config, &record_set, err.as_ref()).await;
+        }
+        reports.push(report);
+        systemd::feed_watchdog();
+    }
+    if families.ipv6 {
+        reports.extend(
+            update_prefix(app_config, route53_client, &mut state).await,
+        );
+    }
+    save_state(app_config, &state_file, &state);
+
+    reports
+}
+
+/// Loads the state kept between runs, when a feature needs it
+fn load_state(
+    app_config: &config::AppConfig,
+    state_file: &str,
+) -> state::State {
+    if app_config.uses_state() {
+        state::State::load(state_file)
+    } else {
+        state::State::default()
+    }
+}
+
+fn save_state(
+    app_config: &config::AppConfig,
+    state_file: &str,
+    state: &state::State,
+) {
+    if !app_config.uses_state() {
+        return;
+    }
+    if let Err(err) = state.save(state_file) {
+        error!("Failed to save the state on {}: {}", state_file, err);
+    }
+}
+
+/// Updates the AAAA records of the hosts on the delegated IPv6 prefix
+async fn update_prefix(
+    config: &config::AppConfig,
+    client: &route53_client::Route53Client,
+    state: &mut state::State,
+) -> Vec<RecordReport> {
+    let prefix_config = match &config.prefix {
+        Some(prefix_config) => prefix_config,
+        None => return Vec::new(),
+    };
+    if prefix_config.interface.is_none() && !ip_address::is_ipv6_available() {
+        return Vec::new();
+    }
+
+    let record_sets = config.prefix_record_sets();
+    let mut reports: Vec<RecordReport> = record_sets
+        .iter()
+        .map(|record_set| {
+            RecordReport::new(record_set.name, record_set.zone_id, true)
+        })
+        .collect();
+
+    let res = update_prefix_hosts(
+        config,
+        prefix_config,
+        client,
+        &record_sets,
+        &mut reports,
+        state,
+    )
+    .await;
+
+    // The hosts are updated together, so they fail together as well
+    if let Err(err) = res {
+        for (record_set, report) in record_sets.iter().zip(reports.iter_mut()) {
+            error!(record = record_set.name; "{}", err);
+            report.error = Some(err.to_string());
+
+            let failures = state.record(record_set).failed();
+            notifications::update_failed(
+                config,
+                record_set,
+                failures,
+                err.as_ref(),
+            );
+            hooks::update_failed(config, record_set, err.as_ref()).await;
+        }
+    }
+
+    reports
+}
+
+async fn update_prefix_hosts(
+    config: &config::AppConfig,
+    prefix_config: &config::PrefixConfig,
+    client: &route53_client::Route53Client,
+    record_sets: &[RecordSet<'_>],
+    reports: &mut [RecordReport],
+    state: &mut state::State,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let zone_id = match record_sets.first() {
+        Some(record_set) => record_set.zone_id,
+        None => return Ok(()),
+    };
+
+    let (prefix, source) =
+        prefix::current(prefix_config, &config.providers(true)).await?;
+    info!(
+        prefix = format!("{}/{}", prefix, prefix_config.length);
+        "Delegated prefix found on {}", source
+    );
+
+    let addresses = prefix::host_addresses(prefix_config, &prefix)?;
+    let hosts = record_sets.iter().zip(reports.iter_mut());
+    for ((record_set, report), (_, ip)) in hosts.zip(addresses.iter()) {
+        metrics::detected_address(record_set, ip);
+        report.detected_ip = Some(*ip);
+        report.provider = Some(source.clone());
+    }
+
+    let changes = client
+        .set_ip_addresses(zone_id, &addresses, config.ttl())
         .await?;
+
+    let updates = record_sets.iter().zip(reports.iter_mut());
+    for ((record_set, report), (change, (_, ip))) in
+        updates.zip(changes.into_iter().zip(addresses.iter()))
+    {
+        metrics::published_address(record_set, Some(ip));
+        let old_ip = match change {
+            RecordSetChange::None | RecordSetChange::Skipped => {
+                report.published_ip = Some(*ip);
+                None
+            }
+            RecordSetChange::Created => {
+                report.action = "created";
+                Some(None)
+            }
+            RecordSetChange::Updated(old_ip) => {
+                report.action = "updated";
+                report.published_ip = old_ip;
+                Some(old_ip)
+            }
+        };
+        if let Some(old_ip) = old_ip {
+            metrics::address_changed(record_set);
+            notifications::address_changed(config, record_set, old_ip, ip);
+            hooks::address_changed(config, record_set, old_ip, ip).await;
+        }
+        metrics::update_succeeded(record_set);
+        state.record(record_set).succeeded();
     }
 
     Ok(())
@@ -111,26 +767,176 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 async fn update_record_set(
     config: &config::AppConfig,
     client: &route53_client::Route53Client,
-    ip_provider: &MyIpProvider,
-    record_set: &str,
+    record_set: &RecordSet<'_>,
+    record_state: &mut state::RecordState,
+    report: &mut RecordReport,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Get current IP Address
-    let my_ipaddr = ip_address::current(ip_provider).await?;
+    let (my_ipaddr, source) =
+        ip_address::record_set_address(record_set).await?;
+    report.provider = Some(source);
+    publish_address(
+        config,
+        client,
+        record_set,
+        my_ipaddr,
+        config.stabilization.as_ref(),
+        record_state,
+        report,
+    )
+    .await
+}
 
-    // Checking if we need to update the recordset
-    let force_update = !config.check_before_updating.unwrap_or(true);
-    if force_update || !is_record_set_up_to_date(record_set, &my_ipaddr).await?
-    {
-        // Updating records
-        client
-            .set_ip_address(&config.zone_id, record_set, &my_ipaddr)
-            .await;
+/// Publishes the address on the record set, unless it is already there.
+/// With `stabilization` a new address waits until it is stable.
+async fn publish_address(
+    config: &config::AppConfig,
+    client: &route53_client::Route53Client,
+    record_set: &RecordSet<'_>,
+    my_ipaddr: IpAddr,
+    stabilization: Option<&config::StabilizationConfig>,
+    record_state: &mut state::RecordState,
+    report: &mut RecordReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let name = record_set.name;
+    metrics::detected_address(record_set, &my_ipaddr);
+    report.detected_ip = Some(my_ipaddr);
+
+    // Checking if we need to update the recordset. The resolver only sees
+    // one of the views of a split-horizon name, or one of the record sets of
+    // a routing policy, so Route53 is asked instead.
+    let force_update = !config.check_before_updating.unwrap_or(true)
+        || config.is_split_horizon(name)
+        || record_set.routing.is_some();
+    if force_update || !is_record_set_up_to_date(name, &my_ipaddr).await? {
+        let countdown = stabilization.and_then(|stabilization| {
+            record_state.observe(my_ipaddr, stabilization, state::now())
+        });
+        if let Some(countdown) = countdown {
+            info!(
+                record = name, ip = my_ipaddr;
+                "{}: {}", name, countdown
+            );
+            report.action = "pending";
+            report.set_pending(&countdown);
+            metrics::update_succeeded(record_set);
+            record_state.succeeded();
+            return Ok(());
+        }
+
+        // Updating records. A new address starts from the lowest adaptive
+        // TTL.
+        let ttl = match &config.adaptive_ttl {
+            Some(adaptive_ttl) => adaptive_ttl.min,
+            None => config.ttl(),
+        };
+        let change = client.set_ip_address(record_set, &my_ipaddr, ttl).await?;
+        match change {
+            RecordSetChange::Skipped => {}
+            RecordSetChange::None => record_state.published(my_ipaddr),
+            _ => {
+                record_state.published(my_ipaddr);
+                record_state.wrote(my_ipaddr, ttl);
+                if let Some(adaptive_ttl) = &config.adaptive_ttl {
+                    record_state.reset_ttl(adaptive_ttl, state::now());
+                }
+            }
+        }
+
+        let old_ip = match change {
+            RecordSetChange::Skipped => {
+                report.action = "skipped";
+                None
+            }
+            RecordSetChange::None => {
+                report.published_ip = Some(my_ipaddr);
+                update_ttl(config, client, record_set, my_ipaddr, record_state)
+                    .await?;
+                None
+            }
+            RecordSetChange::Created => {
+                report.action = "created";
+                Some(None)
+            }
+            RecordSetChange::Updated(old_ip) => {
+                report.action = "updated";
+                report.published_ip = old_ip;
+                Some(old_ip)
+            }
+        };
+        if let Some(old_ip) = old_ip {
+            notifications::address_changed(
+                config, record_set, old_ip, &my_ipaddr,
+            );
+            hooks::address_changed(config, record_set, old_ip, &my_ipaddr)
+                .await;
+        }
     } else {
         // The recordset is already correct; nothing to do
-        let s = format!("{} is up to date.", record_set);
-        println(&s);
+        record_state.published(my_ipaddr);
+        metrics::published_address(record_set, Some(&my_ipaddr));
+        report.published_ip = Some(my_ipaddr);
+        info!(
+            record = name, ip = my_ipaddr;
+            "{} is up to date.", name
+        );
+        update_ttl(config, client, record_set, my_ipaddr, record_state).await?;
     }
 
+    metrics::update_succeeded(record_set);
+    record_state.succeeded();
+    Ok(())
+}
+
+/// Raises the adaptive TTL of an unchanged record set when a step is due
+async fn update_ttl(
+    config: &config::AppConfig,
+    client: &route53_client::Route53Client,
+    record_set: &RecordSet<'_>,
+    ip: IpAddr,
+    record_state: &mut state::RecordState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let adaptive_ttl = match &config.adaptive_ttl {
+        Some(adaptive_ttl) => adaptive_ttl,
+        None => return Ok(()),
+    };
+
+    let previous = record_state.clone();
+    let ttl = record_state.ttl_step(adaptive_ttl, config.ttl(), state::now());
+    if let Some(ttl) = ttl {
+        // A record set changed by someone else is left to the drift
+        // detection, instead of being overwritten
+        if let Some(written) = &previous.written {
+            let res = drift::changed_since(client, record_set, written).await;
+            let description = match res {
+                Ok(description) => description,
+                Err(err) => {
+                    *record_state = previous;
+                    return Err(err);
+                }
+            };
+            if let Some(description) = description {
+                warn!(
+                    record = record_set.name;
+                    "Not changing the TTL of {}: it was changed outside of \
+                     rust-aws-ddns ({})",
+                    record_set.name, description
+                );
+                *record_state = previous;
+                return Ok(());
+            }
+        }
+
+        let res = client.set_ttl(record_set, &ip, ttl).await;
+
+        // Tried again on the next check
+        if res.is_err() {
+            *record_state = previous;
+        }
+        if res? != RecordSetChange::Skipped {
+            record_state.wrote(ip, ttl);
+        }
+    }
     Ok(())
 }
 
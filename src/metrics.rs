@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+
+use crate::config::RecordSet;
+
+/// Labels telling a record set apart: the same name can be on several
+/// zones, for both families and with several set identifiers
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct RecordLabels {
+    zone: String,
+    record: String,
+    family: &'static str,
+    set_identifier: String,
+}
+
+impl RecordLabels {
+    fn new(record_set: &RecordSet<'_>) -> Self {
+        RecordLabels {
+            zone: record_set.zone_id.to_string(),
+            record: record_set.name.to_string(),
+            family: if record_set.is_v6 { "ipv6" } else { "ipv4" },
+            set_identifier: record_set
+                .routing
+                .map(|routing| routing.set_identifier.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl std::fmt::Display for RecordLabels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "zone=\"{}\",record=\"{}\",family=\"{}\",set_identifier=\"{}\"",
+            self.zone, self.record, self.family, self.set_identifier
+        )
+    }
+}
+
+/// Addresses known for a record set
+#[derive(Default)]
+struct RecordAddresses {
+    detected: Option<IpAddr>,
+    published: Option<IpAddr>,
+}
+
+/// Counters and gauges exposed on the metrics endpoint
+#[derive(Default)]
+struct Metrics {
+    last_success: BTreeMap<RecordLabels, f64>,
+    addresses: BTreeMap<RecordLabels, RecordAddresses>,
+    address_changes: BTreeMap<RecordLabels, u64>,
+
+    provider_success: BTreeMap<String, u64>,
+    provider_failure: BTreeMap<String, u64>,
+    provider_latency_sum: BTreeMap<String, f64>,
+    provider_latency_count: BTreeMap<String, u64>,
+
+    route53_calls: BTreeMap<String, u64>,
+    route53_errors: BTreeMap<String, u64>,
+}
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+/// Records the outcome of an IP provider request
+pub fn provider_request(provider: &str, is_success: bool, elapsed: Duration) {
+    let mut metrics = METRICS.lock().unwrap();
+    let provider = provider.to_string();
+
+    let counter = if is_success {
+        &mut metrics.provider_success
+    } else {
+        &mut metrics.provider_failure
+    };
+    *counter.entry(provider.clone()).or_insert(0) += 1;
+
+    *metrics
+        .provider_latency_sum
+        .entry(provider.clone())
+        .or_insert(0.0) += elapsed.as_secs_f64();
+    *metrics.provider_latency_count.entry(provider).or_insert(0) += 1;
+}
+
+/// Records a call to the Route53 API
+pub fn route53_call(operation: &str, is_success: bool) {
+    let mut metrics = METRICS.lock().unwrap();
+
+    *metrics
+        .route53_calls
+        .entry(operation.to_string())
+        .or_insert(0) += 1;
+    if !is_success {
+        *metrics
+            .route53_errors
+            .entry(operation.to_string())
+            .or_insert(0) += 1;
+    }
+}
+
+/// Records the address detected for a record set
+pub fn detected_address(record_set: &RecordSet<'_>, ip: &IpAddr) {
+    let mut metrics = METRICS.lock().unwrap();
+    let entry = metrics
+        .addresses
+        .entry(RecordLabels::new(record_set))
+        .or_default();
+    entry.detected = Some(*ip);
+}
+
+/// Records the address currently published on a record set
+pub fn published_address(record_set: &RecordSet<'_>, ip: Option<&IpAddr>) {
+    let mut metrics = METRICS.lock().unwrap();
+    let entry = metrics
+        .addresses
+        .entry(RecordLabels::new(record_set))
+        .or_default();
+    entry.published = ip.copied();
+}
+
+/// Records that the address of a record set has changed
+pub fn address_changed(record_set: &RecordSet<'_>) {
+    let mut metrics = METRICS.lock().unwrap();
+    *metrics
+        .address_changes
+        .entry(RecordLabels::new(record_set))
+        .or_insert(0) += 1;
+}
+
+/// Records a successful check or update of a record set
+pub fn update_succeeded(record_set: &RecordSet<'_>) {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics
+        .last_success
+        .insert(RecordLabels::new(record_set), now());
+}
+
+/// Writes a metric family, with the labels of each key rendered by `labels`
+fn write_family<K, T: std::fmt::Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    values: &BTreeMap<K, T>,
+    labels: impl Fn(&K) -> String,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (key, value) in values.iter() {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels(key), value);
+    }
+}
+
+fn provider_label(provider: &String) -> String {
+    format!("provider=\"{}\"", provider)
+}
+
+fn operation_label(operation: &String) -> String {
+    format!("operation=\"{}\"", operation)
+}
+
+/// Renders all metrics using the Prometheus text exposition format
+pub fn render() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut out = String::new();
+
+    write_family(
+        &mut out,
+        "rust_aws_ddns_last_success_timestamp_seconds",
+        "gauge",
+        "Time of the last successful check or update of a record set",
+        &metrics.last_success,
+        RecordLabels::to_string,
+    );
+
+    let name = "rust_aws_ddns_record_info";
+    let _ = writeln!(
+        out,
+        "# HELP {} Addresses detected and published for a record set",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (labels, addresses) in metrics.addresses.iter() {
+        let to_label = |ip: &Option<IpAddr>| match ip {
+            Some(ip) => ip.to_string(),
+            None => String::new(),
+        };
+        let _ = writeln!(
+            out,
+            "{}{{{},detected=\"{}\",published=\"{}\"}} 1",
+            name,
+            labels,
+            to_label(&addresses.detected),
+            to_label(&addresses.published),
+        );
+    }
+
+    write_family(
+        &mut out,
+        "rust_aws_ddns_address_changes_total",
+        "counter",
+        "Number of address changes observed for a record set",
+        &metrics.address_changes,
+        RecordLabels::to_string,
+    );
+    write_family(
+        &mut out,
+        "rust_aws_ddns_provider_success_total",
+        "counter",
+        "Successful public IP lookups per provider",
+        &metrics.provider_success,
+        provider_label,
+    );
+    write_family(
+        &mut out,
+        "rust_aws_ddns_provider_failure_total",
+        "counter",
+        "Failed public IP lookups per provider",
+        &metrics.provider_failure,
+        provider_label,
+    );
+
+    let name = "rust_aws_ddns_provider_latency_seconds";
+    let _ = writeln!(out, "# HELP {} Latency of public IP lookups", name);
+    let _ = writeln!(out, "# TYPE {} summary", name);
+    for (provider, sum) in metrics.provider_latency_sum.iter() {
+        let count = metrics.provider_latency_count.get(provider).unwrap_or(&0);
+        let _ =
+            writeln!(out, "{}_sum{{provider=\"{}\"}} {}", name, provider, sum);
+        let _ = writeln!(
+            out,
+            "{}_count{{provider=\"{}\"}} {}",
+            name, provider, count
+        );
+    }
+
+    write_family(
+        &mut out,
+        "rust_aws_ddns_route53_calls_total",
+        "counter",
+        "Calls made to the Route53 API",
+        &metrics.route53_calls,
+        operation_label,
+    );
+    write_family(
+        &mut out,
+        "rust_aws_ddns_route53_errors_total",
+        "counter",
+        "Failed calls to the Route53 API",
+        &metrics.route53_errors,
+        operation_label,
+    );
+
+    out
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = if req.uri().path() == "/metrics" {
+        Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(render()))
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+    };
+
+    Ok(response.unwrap())
+}
+
+/// Binds the metrics endpoint to the specified address, returning the
+/// server to run. Fails if the address can't be used.
+pub fn serve(
+    addr: SocketAddr,
+) -> Result<impl Future<Output = Result<(), hyper::Error>>, hyper::Error> {
+    let make_svc =
+        make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+
+    Ok(Server::try_bind(&addr)?.serve(make_svc))
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+    use crate::config::{ConflictPolicy, DriftPolicy};
+
+    #[test]
+    fn record_labels() {
+        let mut record_set = RecordSet {
+            name: "labels.example.com.",
+            zone_id: "Z1",
+            is_v6: false,
+            providers: Vec::new(),
+            interface: None,
+            on_drift: DriftPolicy::Ignore,
+            on_conflict: ConflictPolicy::Refuse,
+            routing: None,
+        };
+        update_succeeded(&record_set);
+        record_set.is_v6 = true;
+        update_succeeded(&record_set);
+
+        // Each family has a series of its own
+        let out = render();
+        assert!(out.contains(
+            "rust_aws_ddns_last_success_timestamp_seconds{zone=\"Z1\",\
+             record=\"labels.example.com.\",family=\"ipv4\",set_identifier=\"\"}"
+        ));
+        assert!(out.contains("record=\"labels.example.com.\",family=\"ipv6\""));
+    }
+}
@@ -0,0 +1,263 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+/// Multicast groups of the address and route changes (`RTMGRP_*`)
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+const RTMGRP_IPV6_ROUTE: u32 = 0x400;
+
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+
+/// Time without further changes before checking the addresses again
+pub const DEBOUNCE: Duration = Duration::from_secs(2);
+
+const NLMSG_HDRLEN: usize = 16;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_TABLE_MAIN: u8 = 254;
+
+/// Address families that need to be checked again
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Families {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+impl Families {
+    pub const ALL: Families = Families {
+        ipv4: true,
+        ipv6: true,
+    };
+
+    pub fn contains(&self, is_v6: bool) -> bool {
+        if is_v6 {
+            self.ipv6
+        } else {
+            self.ipv4
+        }
+    }
+
+    pub fn add(&mut self, other: Families) {
+        self.ipv4 |= other.ipv4;
+        self.ipv6 |= other.ipv6;
+    }
+}
+
+/// Returns the family affected by the message, if it is about a global
+/// address or a default route
+fn affected_family(msg_type: u16, payload: &[u8]) -> Option<bool> {
+    let family = *payload.first()?;
+    let is_relevant = match msg_type {
+        // struct ifaddrmsg: family, prefixlen, flags, scope, index
+        RTM_NEWADDR | RTM_DELADDR => *payload.get(3)? == RT_SCOPE_UNIVERSE,
+        // struct rtmsg: family, dst_len, src_len, tos, table, ...
+        RTM_NEWROUTE | RTM_DELROUTE => {
+            *payload.get(1)? == 0 && *payload.get(4)? == RT_TABLE_MAIN
+        }
+        _ => false,
+    };
+    if !is_relevant {
+        return None;
+    }
+
+    match i32::from(family) {
+        libc::AF_INET => Some(false),
+        libc::AF_INET6 => Some(true),
+        _ => None,
+    }
+}
+
+/// Returns the families affected by the messages on a netlink datagram
+fn parse_messages(mut buf: &[u8]) -> Families {
+    let mut families = Families::default();
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let msg_type = u16::from_ne_bytes([buf[4], buf[5]]);
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            break;
+        }
+
+        match affected_family(msg_type, &buf[NLMSG_HDRLEN..len]) {
+            Some(true) => families.ipv6 = true,
+            Some(false) => families.ipv4 = true,
+            None => {}
+        }
+
+        // Messages are aligned to 4 bytes
+        let aligned = (len + 3) & !3;
+        buf = &buf[aligned.min(buf.len())..];
+    }
+    families
+}
+
+fn open_socket() -> io::Result<RawFd> {
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut addr: libc::sockaddr_nl = mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = RTMGRP_IPV4_IFADDR
+            | RTMGRP_IPV4_ROUTE
+            | RTMGRP_IPV6_IFADDR
+            | RTMGRP_IPV6_ROUTE;
+
+        let res = libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        );
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            libc::close(fd);
+            return Err(err);
+        }
+
+        Ok(fd)
+    }
+}
+
+/// Subscribes to the rtnetlink address and route events, sending the
+/// families affected by each change of a global address or default route
+pub fn subscribe() -> io::Result<mpsc::UnboundedReceiver<Families>> {
+    let fd = open_socket()?;
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    // The socket is blocking, so it gets a thread of its own
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; 16384];
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    fd,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    // The kernel dropped events: anything may have changed
+                    _ if err.raw_os_error() == Some(libc::ENOBUFS) => {
+                        if sender.send(Families::ALL).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    _ => {
+                        error!("Netlink socket failed: {}", err);
+                        break;
+                    }
+                }
+            }
+
+            let families = parse_messages(&buf[..len as usize]);
+            if families != Families::default() && sender.send(families).is_err()
+            {
+                break;
+            }
+        }
+        unsafe { libc::close(fd) };
+    });
+
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod netlink_tests {
+    use super::*;
+
+    fn message(msg_type: u16, payload: &[u8]) -> Vec<u8> {
+        let len = (NLMSG_HDRLEN + payload.len()) as u32;
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&len.to_ne_bytes());
+        msg.extend_from_slice(&msg_type.to_ne_bytes());
+        msg.extend_from_slice(&[0; 10]);
+        msg.extend_from_slice(payload);
+        while msg.len() % 4 != 0 {
+            msg.push(0);
+        }
+        msg
+    }
+
+    #[test]
+    fn messages() {
+        let inet = libc::AF_INET as u8;
+        let inet6 = libc::AF_INET6 as u8;
+
+        // Global IPv6 address and a link-local one
+        let mut buf = message(RTM_NEWADDR, &[inet6, 64, 0, 0, 2, 0, 0, 0]);
+        buf.extend(message(RTM_DELADDR, &[inet, 24, 0, 253, 2, 0, 0, 0]));
+        assert_eq!(
+            parse_messages(&buf),
+            Families {
+                ipv4: false,
+                ipv6: true
+            }
+        );
+
+        // Default route on the main table and a route to a subnet
+        let mut buf = message(RTM_NEWROUTE, &[inet, 0, 0, 0, 254, 3, 0, 1]);
+        buf.extend(message(RTM_NEWROUTE, &[inet6, 48, 0, 0, 254, 3, 0, 1]));
+        assert_eq!(
+            parse_messages(&buf),
+            Families {
+                ipv4: true,
+                ipv6: false
+            }
+        );
+
+        // Truncated messages are ignored
+        assert_eq!(parse_messages(&buf[..10]), Families::default());
+    }
+
+    fn ip(args: &[&str]) {
+        let status = std::process::Command::new("ip").args(args).status();
+        assert!(status.unwrap().success(), "ip {}", args.join(" "));
+    }
+
+    /// Adds a global address on the loopback interface of a network
+    /// namespace of its own, and waits for the event
+    #[test]
+    #[ignore = "needs root to create a network namespace"]
+    fn subscription() {
+        // Only the thread (and the commands it runs) enters the namespace
+        let test = std::thread::spawn(|| {
+            if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+                panic!("unshare: {}", io::Error::last_os_error());
+            }
+            let mut receiver = subscribe().unwrap();
+
+            ip(&["link", "set", "lo", "up"]);
+            ip(&["address", "add", "192.0.2.1/24", "dev", "lo"]);
+
+            let deadline = std::time::Instant::now() + Duration::from_secs(5);
+            loop {
+                match receiver.try_recv() {
+                    Ok(families) if families.ipv4 => break,
+                    Ok(_) => {}
+                    Err(_) => {
+                        assert!(std::time::Instant::now() < deadline);
+                        std::thread::sleep(Duration::from_millis(50));
+                    }
+                }
+            }
+        });
+        test.join().unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::{AppConfig, RecordSet, WebhookConfig};
+
+/// Number of delivery attempts when the webhook doesn't set `retries`
+const DEFAULT_RETRIES: u32 = 3;
+
+/// Time a webhook request may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a delivery may take, retries included
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Deliveries still running in the background
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Payload posted to the webhooks
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    event: &'a str,
+    record: &'a str,
+    zone: &'a str,
+    old_ip: Option<IpAddr>,
+    new_ip: Option<IpAddr>,
+    error: Option<String>,
+    failures: u32,
+    timestamp: String,
+}
+
+impl Notification<'_> {
+    /// Renders the webhook body, using its template if one is configured
+    fn render(&self, webhook: &WebhookConfig) -> String {
+        let template = match &webhook.template {
+            Some(template) => template,
+            None => return serde_json::to_string(self).unwrap(),
+        };
+
+        let is_json = match &webhook.content_type {
+            Some(content_type) => content_type.contains("json"),
+            None => true,
+        };
+        let escape = |value: String| {
+            if is_json {
+                let quoted = serde_json::to_string(&value).unwrap();
+                String::from(&quoted[1..quoted.len() - 1])
+            } else {
+                value
+            }
+        };
+        let to_string =
+            |ip: Option<IpAddr>| ip.map_or(String::new(), |ip| ip.to_string());
+
+        template
+            .replace("{{event}}", &escape(self.event.to_string()))
+            .replace("{{record}}", &escape(self.record.to_string()))
+            .replace("{{zone}}", &escape(self.zone.to_string()))
+            .replace("{{old_ip}}", &escape(to_string(self.old_ip)))
+            .replace("{{new_ip}}", &escape(to_string(self.new_ip)))
+            .replace(
+                "{{error}}",
+                &escape(self.error.clone().unwrap_or_default()),
+            )
+            .replace("{{failures}}", &self.failures.to_string())
+            .replace("{{timestamp}}", &escape(self.timestamp.clone()))
+    }
+}
+
+/// Posts the body to the webhook, retrying failed deliveries
+async fn deliver(webhook: &WebhookConfig, body: String) {
+    let client =
+        match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+            Ok(client) => client,
+            Err(err) => {
+                warn!("Webhook {} failed: {}", webhook.url, err);
+                return;
+            }
+        };
+    let content_type = webhook
+        .content_type
+        .as_deref()
+        .unwrap_or("application/json");
+    let attempts = webhook.retries.unwrap_or(DEFAULT_RETRIES) + 1;
+
+    for attempt in 1..=attempts {
+        let res = client
+            .post(&webhook.url)
+            .header("Content-Type", content_type)
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+
+        match res {
+            Ok(_) => return,
+            Err(err) => warn!(
+                "Webhook {} failed (attempt {} of {}): {}",
+                webhook.url, attempt, attempts, err
+            ),
+        }
+
+        if attempt < attempts {
+            let backoff = Duration::from_secs(2u64.pow(attempt.min(5)));
+            tokio::time::delay_for(backoff).await;
+        }
+    }
+}
+
+/// Delivers the notification in the background, so that a slow webhook
+/// doesn't hold up the updates
+fn send(webhook: &WebhookConfig, body: String) {
+    let webhook = webhook.clone();
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    tokio::spawn(async move {
+        let res =
+            tokio::time::timeout(DELIVERY_TIMEOUT, deliver(&webhook, body))
+                .await;
+        if res.is_err() {
+            warn!(
+                "Webhook {} gave up after {}s",
+                webhook.url,
+                DELIVERY_TIMEOUT.as_secs()
+            );
+        }
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Waits for the deliveries still running, before exiting
+pub async fn flush() {
+    let deadline = Instant::now() + DELIVERY_TIMEOUT;
+    while PENDING.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+}
+
+/// Returns true if the streak reached the threshold on its last failure, so
+/// each streak is notified once
+fn crosses_threshold(failures: u32, threshold: Option<u32>) -> bool {
+    match threshold {
+        Some(threshold) => failures >= threshold && failures - 1 < threshold,
+        None => false,
+    }
+}
+
+/// Notifies the webhooks that the address of a record set has changed
+pub fn address_changed(
+    config: &AppConfig,
+    record_set: &RecordSet<'_>,
+    old_ip: Option<IpAddr>,
+    new_ip: &IpAddr,
+) {
+    let webhooks = match &config.webhooks {
+        Some(webhooks) => webhooks,
+        None => return,
+    };
+
+    let notification = Notification {
+        event: "changed",
+        record: record_set.name,
+        zone: record_set.zone_id,
+        old_ip,
+        new_ip: Some(*new_ip),
+        error: None,
+        failures: 0,
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+
+    for webhook in webhooks.iter() {
+        send(webhook, notification.render(webhook));
+    }
+}
+
+/// Notifies the webhooks that have opted in about repeated update failures,
+/// `failures` being the length of the current streak (kept on the state)
+pub fn update_failed(
+    config: &AppConfig,
+    record_set: &RecordSet<'_>,
+    failures: u32,
+    error: &dyn std::error::Error,
+) {
+    let webhooks = match &config.webhooks {
+        Some(webhooks) => webhooks,
+        None => return,
+    };
+
+    let notification = Notification {
+        event: "failed",
+        record: record_set.name,
+        zone: record_set.zone_id,
+        old_ip: None,
+        new_ip: None,
+        error: Some(error.to_string()),
+        failures,
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+
+    // Notifying once per streak, when the threshold is reached
+    for webhook in webhooks.iter() {
+        if crosses_threshold(failures, webhook.notify_failures_after) {
+            send(webhook, notification.render(webhook));
+        }
+    }
+}
+
+/// Notifies the webhooks that the record set was changed outside of the tool,
+/// with the differences on `error`
+pub fn drift_detected(
+    config: &AppConfig,
+    record_set: &RecordSet<'_>,
+    description: &str,
+) {
+    let webhooks = match &config.webhooks {
+        Some(webhooks) => webhooks,
+        None => return,
+    };
+
+    let notification = Notification {
+        event: "drift",
+        record: record_set.name,
+        zone: record_set.zone_id,
+        old_ip: None,
+        new_ip: None,
+        error: Some(description.to_string()),
+        failures: 0,
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+
+    for webhook in webhooks.iter() {
+        send(webhook, notification.render(webhook));
+    }
+}
+
+#[cfg(test)]
+mod notification_tests {
+    use super::{crosses_threshold, Notification};
+    use crate::config::WebhookConfig;
+
+    fn notification() -> Notification<'static> {
+        Notification {
+            event: "changed",
+            record: "home.example.com.",
+            zone: "Z123",
+            old_ip: Some("192.0.2.1".parse().unwrap()),
+            new_ip: Some("192.0.2.2".parse().unwrap()),
+            error: None,
+            failures: 0,
+            timestamp: String::from("2020-01-01T00:00:00+00:00"),
+        }
+    }
+
+    fn webhook(
+        template: Option<&str>,
+        content_type: Option<&str>,
+    ) -> WebhookConfig {
+        WebhookConfig {
+            url: String::from("http://localhost/"),
+            template: template.map(String::from),
+            content_type: content_type.map(String::from),
+            retries: None,
+            notify_failures_after: None,
+        }
+    }
+
+    #[test]
+    fn default_payload() {
+        let body = notification().render(&webhook(None, None));
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(value["record"], "home.example.com.");
+        assert_eq!(value["old_ip"], "192.0.2.1");
+        assert_eq!(value["new_ip"], "192.0.2.2");
+        assert_eq!(value["zone"], "Z123");
+    }
+
+    #[test]
+    fn templated_payload() {
+        let slack =
+            webhook(Some(r#"{"text": "{{record}} is now {{new_ip}}"}"#), None);
+        assert_eq!(
+            notification().render(&slack),
+            r#"{"text": "home.example.com. is now 192.0.2.2"}"#
+        );
+
+        let mut failed = notification();
+        failed.error = Some(String::from("\"quoted\""));
+        let json = webhook(Some(r#"{"text": "{{error}}"}"#), None);
+        assert_eq!(failed.render(&json), r#"{"text": "\"quoted\""}"#);
+
+        let ntfy = webhook(Some("{{error}} ({{old_ip}})"), Some("text/plain"));
+        assert_eq!(failed.render(&ntfy), "\"quoted\" (192.0.2.1)");
+    }
+
+    #[test]
+    fn failure_streaks() {
+        assert!(!crosses_threshold(1, Some(2)));
+        assert!(crosses_threshold(2, Some(2)));
+        assert!(!crosses_threshold(3, Some(2)));
+        assert!(!crosses_threshold(2, None));
+    }
+}
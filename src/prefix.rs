@@ -0,0 +1,156 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use crate::config::{PrefixConfig, PrefixHostConfig};
+use crate::ip_address::{self, MyIpProvider};
+
+/// Returns the mask of the first `length` bits of an IPv6 address
+fn prefix_mask(length: u8) -> u128 {
+    match length {
+        0 => 0,
+        length => !0u128 << (128 - u32::from(length.min(128))),
+    }
+}
+
+/// Returns the modified EUI-64 interface identifier of the MAC address
+/// (RFC 4291, appendix A)
+fn eui64(mac: &str) -> Result<u64, String> {
+    let octets: Vec<u8> = mac
+        .split([':', '-'])
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid MAC address \"{}\"", mac))?;
+    if octets.len() != 6 {
+        return Err(format!("invalid MAC address \"{}\"", mac));
+    }
+
+    let eui = [
+        octets[0] ^ 0x02,
+        octets[1],
+        octets[2],
+        0xFF,
+        0xFE,
+        octets[3],
+        octets[4],
+        octets[5],
+    ];
+    Ok(u64::from_be_bytes(eui))
+}
+
+/// Returns the host part of the address of the host on a prefix of the
+/// specified length
+pub fn host_suffix(
+    host: &PrefixHostConfig,
+    length: u8,
+) -> Result<u128, String> {
+    let suffix = match (&host.interface_id, &host.mac) {
+        (Some(interface_id), None) => {
+            if host.subnet.is_some() {
+                return Err(String::from("subnet is only used along with mac"));
+            }
+            let interface_id: Ipv6Addr =
+                interface_id.parse().map_err(|_| {
+                    format!("invalid interface_id \"{}\"", interface_id)
+                })?;
+            u128::from(interface_id)
+        }
+        (None, Some(mac)) => {
+            let subnet = u128::from(host.subnet.unwrap_or(0));
+            (subnet << 64) | u128::from(eui64(mac)?)
+        }
+        _ => return Err(String::from("set either interface_id or mac")),
+    };
+
+    if suffix & prefix_mask(length) != 0 {
+        return Err(format!("the host part overlaps the /{} prefix", length));
+    }
+    Ok(suffix)
+}
+
+/// Returns the address of the host part on the prefix
+pub fn host_address(prefix: &Ipv6Addr, length: u8, suffix: u128) -> Ipv6Addr {
+    Ipv6Addr::from((u128::from(*prefix) & prefix_mask(length)) | suffix)
+}
+
+/// Finds the current delegated prefix: the address of the interface or, if
+/// there is none configured, the one reported by the providers. Returns it
+/// along with where it came from.
+pub async fn current(
+    config: &PrefixConfig,
+    providers: &[MyIpProvider],
+) -> Result<(Ipv6Addr, String), Box<dyn std::error::Error>> {
+    let (ip, source) = match &config.interface {
+        Some(interface) => {
+            let (ip, _) = ip_address::interface_address(interface, true, true)?;
+            (ip, format!("interface {}", interface))
+        }
+        None => {
+            let (ip, provider) = ip_address::current(providers, true).await?;
+            (ip, ip_address::provider_label(&provider))
+        }
+    };
+
+    match ip {
+        IpAddr::V6(ip) => Ok((host_address(&ip, config.length, 0), source)),
+        IpAddr::V4(_) => {
+            Err("the prefix source returned an IPv4 address".into())
+        }
+    }
+}
+
+/// Returns the name and address of every host on the prefix
+pub fn host_addresses<'a>(
+    config: &'a PrefixConfig,
+    prefix: &Ipv6Addr,
+) -> Result<Vec<(&'a str, IpAddr)>, String> {
+    config
+        .hosts
+        .iter()
+        .map(|host| {
+            let suffix = host_suffix(host, config.length)
+                .map_err(|err| format!("{}: {}", host.name, err))?;
+            let ip = host_address(prefix, config.length, suffix);
+            Ok((host.name.as_str(), IpAddr::V6(ip)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod prefix_tests {
+    use super::*;
+
+    fn host(interface_id: Option<&str>, mac: Option<&str>) -> PrefixHostConfig {
+        PrefixHostConfig {
+            name: String::from("nas.example.com."),
+            interface_id: interface_id.map(String::from),
+            mac: mac.map(String::from),
+            subnet: None,
+        }
+    }
+
+    #[test]
+    fn host_addresses() {
+        let prefix: Ipv6Addr = "2001:db8:aa:bb00:1:2:3:4".parse().unwrap();
+
+        let suffix = host_suffix(&host(Some("::10"), None), 64).unwrap();
+        assert_eq!(
+            host_address(&prefix, 64, suffix),
+            "2001:db8:aa:bb00::10".parse::<Ipv6Addr>().unwrap()
+        );
+
+        let mut eui = host(None, Some("00:11:22:33:44:55"));
+        eui.subnet = Some(2);
+        let suffix = host_suffix(&eui, 56).unwrap();
+        assert_eq!(
+            host_address(&prefix, 56, suffix),
+            "2001:db8:aa:bb02:211:22ff:fe33:4455"
+                .parse::<Ipv6Addr>()
+                .unwrap()
+        );
+
+        // The subnet doesn't fit on a /64
+        assert!(host_suffix(&eui, 64).is_err());
+        assert!(host_suffix(&host(Some("1::10"), None), 64).is_err());
+        assert!(host_suffix(&host(None, Some("00:11:22:33:44")), 64).is_err());
+        assert!(host_suffix(&host(None, None), 64).is_err());
+    }
+}
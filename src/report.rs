@@ -0,0 +1,70 @@
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+use crate::state::Countdown;
+
+/// Outcome of checking or updating a record set, used on the JSON output
+#[derive(Debug, Default, Serialize)]
+pub struct RecordReport {
+    pub name: String,
+    pub zone: String,
+    pub family: &'static str,
+    pub detected_ip: Option<IpAddr>,
+
+    /// Address found on the record set before any change was made
+    pub published_ip: Option<IpAddr>,
+
+    /// `none`, `created`, `updated`, `pending` or `skipped`
+    pub action: &'static str,
+    pub provider: Option<String>,
+    pub error: Option<String>,
+
+    /// Address waiting to be stable before being published, along with the
+    /// checks and seconds left
+    pub pending_ip: Option<IpAddr>,
+    pub pending_checks_left: Option<u32>,
+    pub pending_seconds_left: Option<i64>,
+}
+
+impl RecordReport {
+    pub fn new(name: &str, zone: &str, is_v6: bool) -> Self {
+        RecordReport {
+            name: name.to_string(),
+            zone: zone.to_string(),
+            family: if is_v6 { "ipv6" } else { "ipv4" },
+            action: "none",
+            ..Default::default()
+        }
+    }
+
+    pub fn set_pending(&mut self, countdown: &Countdown) {
+        self.pending_ip = Some(countdown.ip);
+        self.pending_checks_left = countdown.checks_left;
+        self.pending_seconds_left = countdown.seconds_left;
+    }
+}
+
+/// Output format of the `run` and `status` results
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(OutputFormat::Text),
+            "json" => Some(OutputFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Prints one JSON object per record set
+pub fn print_json(reports: &[RecordReport]) {
+    for report in reports.iter() {
+        println!("{}", serde_json::to_string(report).unwrap());
+    }
+}
@@ -1,17 +1,109 @@
 use std::net::IpAddr;
 
 use crate::aws_credentials::AppAwsCredentials;
+use crate::config::{ConflictPolicy, RecordSet, Routing};
 
 use rusoto_core::Region;
-use rusoto_route53::{Route53, Route53Client as AwsRoute53Client};
+use rusoto_route53::{
+    Change, ResourceRecordSet, Route53, Route53Client as AwsRoute53Client,
+};
+
+/// Change made to a record set by `set_ip_address`
+#[derive(Debug, PartialEq)]
+pub enum RecordSetChange {
+    /// The record set already pointed to the address
+    None,
+    /// The record set didn't exist and was created
+    Created,
+    /// The record set was updated from the previous address
+    Updated(Option<IpAddr>),
+    /// The record set isn't a simple one and `on_conflict` is `skip`
+    Skipped,
+}
+
+/// Prefix of the TXT record set holding the ownership marker of a record set
+const OWNER_RECORD_PREFIX: &str = "_rust-aws-ddns.";
+
+/// Owner id when the configuration doesn't set one
+pub const DEFAULT_OWNER: &str = "default";
+
+/// Who manages a record set, according to its ownership marker
+#[derive(Debug, PartialEq)]
+enum Ownership {
+    Owned,
+    Unmarked,
+    /// Managed by the tool with another owner id
+    Foreign(String),
+}
 
 pub struct Route53Client {
     credentials: Option<AppAwsCredentials>,
+    owner: String,
+
+    /// Takes over existing record sets without a matching ownership marker
+    adopt: bool,
 }
 
 impl Route53Client {
     pub fn new(credentials: Option<AppAwsCredentials>) -> Self {
-        Route53Client { credentials }
+        Route53Client {
+            credentials,
+            owner: String::from(DEFAULT_OWNER),
+            adopt: false,
+        }
+    }
+
+    /// Sets the owner id written on the ownership markers, and whether
+    /// record sets managed by others can be taken over
+    pub fn with_ownership(mut self, owner: &str, adopt: bool) -> Self {
+        self.owner = String::from(owner);
+        self.adopt = adopt;
+        self
+    }
+
+    /// Returns the ownership marker to write along with a change of the
+    /// record set: on creation and when adopting it. Fails if the record
+    /// set isn't ours and can't be adopted.
+    fn ownership_changes(
+        &self,
+        record_set: &str,
+        set_identifier: Option<&str>,
+        state: &RecordSetState,
+        ttl: i64,
+    ) -> Result<Vec<ResourceRecordSet>, Box<dyn std::error::Error>> {
+        let marker = vec![marker_record_set(
+            record_set,
+            state.marker.as_ref(),
+            &self.owner,
+            set_identifier,
+            ttl,
+        )];
+        if !state.is_present {
+            return Ok(marker);
+        }
+
+        match &state.ownership {
+            Ownership::Owned => Ok(Vec::new()),
+            _ if self.adopt => {
+                info!(
+                    record = record_set, owner = self.owner.as_str();
+                    "Adopting \"{}\"", record_set
+                );
+                Ok(marker)
+            }
+            Ownership::Unmarked => Err(format!(
+                "\"{}\" already exists and isn't managed by rust-aws-ddns, \
+                 use --adopt to take it over",
+                record_set
+            )
+            .into()),
+            Ownership::Foreign(owner) => Err(format!(
+                "\"{}\" is managed by the rust-aws-ddns owner \"{}\", \
+                 use --adopt to take it over",
+                record_set, owner
+            )
+            .into()),
+        }
     }
 
     fn new_client(&self) -> AwsRoute53Client {
@@ -32,6 +124,55 @@ impl Route53Client {
         }
     }
 
+    /// Returns the changes pointing the record set to the address: the
+    /// ownership marker, the record set itself and, when replacing record
+    /// sets of another kind, their deletion. None if it has to be left alone.
+    fn planned_changes(
+        &self,
+        record_set: &RecordSet<'_>,
+        state: &RecordSetState,
+        ip: &IpAddr,
+        ttl: i64,
+    ) -> Result<Option<Vec<Change>>, Box<dyn std::error::Error>> {
+        let name = record_set.name;
+        let mut changes = Vec::new();
+        if let Some(conflict) = &state.conflict {
+            match record_set.on_conflict {
+                ConflictPolicy::Refuse => {
+                    return Err(format!(
+                        "\"{}\" {}; set on_conflict to replace or skip to \
+                         handle it",
+                        name, conflict
+                    )
+                    .into())
+                }
+                ConflictPolicy::Skip => {
+                    warn!(record = name; "Leaving \"{}\" alone: it {}", name, conflict);
+                    return Ok(None);
+                }
+                ConflictPolicy::Replace => {
+                    warn!(record = name; "Replacing \"{}\": it {}", name, conflict);
+                    changes.extend(
+                        state
+                            .conflicting
+                            .iter()
+                            .map(|entry| change("DELETE", entry.clone())),
+                    );
+                }
+            }
+        }
+
+        let routing = record_set.routing.as_ref();
+        let set_identifier = routing.map(|routing| routing.set_identifier);
+        let markers =
+            self.ownership_changes(name, set_identifier, state, ttl)?;
+        changes
+            .extend(markers.into_iter().map(|marker| change("UPSERT", marker)));
+        let address = address_record_set(name, ip, ttl, routing);
+        changes.push(change("UPSERT", address));
+        Ok(Some(changes))
+    }
+
     pub async fn list_hosted_zones(&self) -> Option<Vec<(String, String)>> {
         let client = self.new_client();
 
@@ -42,6 +183,7 @@ impl Route53Client {
         };
 
         let result = client.list_hosted_zones(request).await;
+        crate::metrics::route53_call("ListHostedZones", result.is_ok());
         match result {
             Err(_) => None,
             Ok(res) => {
@@ -57,49 +199,606 @@ impl Route53Client {
         }
     }
 
-    pub async fn set_ip_address(
+    /// Returns the name servers the hosted zone is delegated to. Private
+    /// hosted zones don't have any.
+    pub async fn get_name_servers(
+        &self,
+        zone_id: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let client = self.new_client();
+
+        let request = rusoto_route53::GetHostedZoneRequest {
+            id: zone_id.trim_start_matches("/hostedzone/").to_string(),
+        };
+        let result = client.get_hosted_zone(request).await;
+        crate::metrics::route53_call("GetHostedZone", result.is_ok());
+
+        let name_servers = result?
+            .delegation_set
+            .map(|delegation_set| delegation_set.name_servers)
+            .unwrap_or_default();
+        Ok(name_servers)
+    }
+
+    /// Creates a public hosted zone for the domain, returning its id, name
+    /// and the name servers it must be delegated to
+    pub async fn create_hosted_zone(
+        &self,
+        domain: &str,
+    ) -> Result<(String, String, Vec<String>), Box<dyn std::error::Error>> {
+        let client = self.new_client();
+
+        // The caller reference only has to be unique per request
+        let caller_reference = format!(
+            "rust-aws-ddns-{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_millis()
+        );
+        let request = rusoto_route53::CreateHostedZoneRequest {
+            caller_reference,
+            name: domain.to_string(),
+            ..Default::default()
+        };
+        let result = client.create_hosted_zone(request).await;
+        crate::metrics::route53_call("CreateHostedZone", result.is_ok());
+
+        let response = result?;
+        Ok((
+            response.hosted_zone.id,
+            response.hosted_zone.name,
+            response.delegation_set.name_servers,
+        ))
+    }
+
+    /// Returns all the record sets of the hosted zone
+    pub async fn list_record_sets(
+        &self,
+        zone_id: &str,
+    ) -> Result<Vec<ResourceRecordSet>, Box<dyn std::error::Error>> {
+        let client = self.new_client();
+
+        let mut record_sets = Vec::new();
+        fetch_record_sets(&client, zone_id, |entry| {
+            record_sets.push(entry.clone());
+            true
+        })
+        .await?;
+
+        Ok(record_sets)
+    }
+
+    /// Returns the record set with the specified name and type, and the set
+    /// identifier if it has one, if present
+    pub async fn get_record_set(
         &self,
         zone_id: &str,
         record_set: &str,
+        record_type: &str,
+        set_identifier: Option<&str>,
+    ) -> Result<Option<ResourceRecordSet>, Box<dyn std::error::Error>> {
+        let client = self.new_client();
+        find_record_set(
+            &client,
+            zone_id,
+            record_set,
+            record_type,
+            set_identifier,
+        )
+        .await
+    }
+
+    pub async fn set_ip_address(
+        &self,
+        target: &RecordSet<'_>,
         ip: &IpAddr,
-    ) {
-        crate::println(&format!("Updating \"{}\" to {}", record_set, ip));
+        ttl: i64,
+    ) -> Result<RecordSetChange, Box<dyn std::error::Error>> {
+        let (zone_id, record_set) = (target.zone_id, target.name);
+        info!(
+            record = record_set, zone = zone_id, ip = ip;
+            "Updating \"{}\" to {}", record_set, ip
+        );
 
         let client = self.new_client();
-        let (is_recordset_present, is_recordset_uptodate) =
-            check_record_set(&client, zone_id, record_set, ip).await;
+        let state = check_record_set(&client, target, ip, &self.owner).await?;
+        crate::metrics::published_address(target, state.published.as_ref());
 
         // Already up to date, nothing to do
-        if is_recordset_present && is_recordset_uptodate {
-            crate::println(&format!(
-                "   {} is already up to date.",
-                record_set
-            ));
-            return;
+        if state.is_present && state.is_up_to_date {
+            info!(
+                record = record_set, zone = zone_id, ip = ip;
+                "{} is already up to date.", record_set
+            );
+            return Ok(RecordSetChange::None);
         }
 
         // We need to update / create the recordset
-        update_record_set(&client, zone_id, record_set, ip).await;
-        crate::println(&format!("   {} was updated.", record_set));
+        let changes = match self.planned_changes(target, &state, ip, ttl)? {
+            Some(changes) => changes,
+            None => return Ok(RecordSetChange::Skipped),
+        };
+        change_record_sets(&client, zone_id, changes).await?;
+        crate::metrics::published_address(target, Some(ip));
+        crate::metrics::address_changed(target);
+        info!(
+            record = record_set, zone = zone_id, ip = ip;
+            "{} was updated.", record_set
+        );
+
+        if state.is_present {
+            Ok(RecordSetChange::Updated(state.published))
+        } else {
+            Ok(RecordSetChange::Created)
+        }
+    }
+
+    /// Changes the TTL of the record set, keeping its address
+    pub async fn set_ttl(
+        &self,
+        target: &RecordSet<'_>,
+        ip: &IpAddr,
+        ttl: i64,
+    ) -> Result<RecordSetChange, Box<dyn std::error::Error>> {
+        let (zone_id, record_set) = (target.zone_id, target.name);
+        info!(
+            record = record_set, zone = zone_id, ttl = ttl;
+            "Changing the TTL of \"{}\" to {}s", record_set, ttl
+        );
+
+        let client = self.new_client();
+        let state = check_record_set(&client, target, ip, &self.owner).await?;
+        match self.planned_changes(target, &state, ip, ttl)? {
+            Some(changes) => {
+                change_record_sets(&client, zone_id, changes).await?;
+                Ok(RecordSetChange::Updated(state.published))
+            }
+            None => Ok(RecordSetChange::Skipped),
+        }
+    }
+
+    /// Returns every record set with the name, whatever its type
+    pub async fn get_record_sets_named(
+        &self,
+        zone_id: &str,
+        record_set: &str,
+    ) -> Result<Vec<ResourceRecordSet>, Box<dyn std::error::Error>> {
+        let client = self.new_client();
+
+        let mut record_sets = Vec::new();
+        fetch_record_sets(&client, zone_id, |entry| {
+            if is_same_name(&entry.name, record_set) {
+                record_sets.push(entry.clone());
+            }
+            true
+        })
+        .await?;
+
+        Ok(record_sets)
+    }
+
+    /// Publishes the address and TTL again on a record set changed by
+    /// someone else
+    pub async fn restore_record_set(
+        &self,
+        target: &RecordSet<'_>,
+        ip: &IpAddr,
+        ttl: i64,
+    ) -> Result<RecordSetChange, Box<dyn std::error::Error>> {
+        let (zone_id, record_set) = (target.zone_id, target.name);
+        info!(
+            record = record_set, zone = zone_id, ip = ip, ttl = ttl;
+            "Restoring \"{}\" to {} with a TTL of {}s", record_set, ip, ttl
+        );
+
+        let client = self.new_client();
+        let state = check_record_set(&client, target, ip, &self.owner).await?;
+        match self.planned_changes(target, &state, ip, ttl)? {
+            Some(changes) => {
+                change_record_sets(&client, zone_id, changes).await?;
+                Ok(RecordSetChange::Updated(state.published))
+            }
+            None => Ok(RecordSetChange::Skipped),
+        }
+    }
+
+    /// Points each name to its address, reading the zone once and sending
+    /// every change on a single change batch. Returns the change made to
+    /// each record set, in the same order.
+    pub async fn set_ip_addresses(
+        &self,
+        zone_id: &str,
+        addresses: &[(&str, IpAddr)],
+        ttl: i64,
+    ) -> Result<Vec<RecordSetChange>, Box<dyn std::error::Error>> {
+        let client = self.new_client();
+
+        let mut found: Vec<Vec<ResourceRecordSet>> =
+            vec![Vec::new(); addresses.len()];
+        let mut markers: Vec<Option<ResourceRecordSet>> =
+            vec![None; addresses.len()];
+        fetch_record_sets(&client, zone_id, |entry| {
+            for (idx, (name, ip)) in addresses.iter().enumerate() {
+                if is_same_name(&entry.name, name)
+                    && entry.type_ == record_type(ip)
+                {
+                    found[idx].push(entry.clone());
+                } else if is_marker_of(entry, name) {
+                    markers[idx] = Some(entry.clone());
+                }
+            }
+            true
+        })
+        .await?;
+
+        let mut changes = Vec::new();
+        let mut record_sets = Vec::new();
+        let entries = addresses.iter().zip(found).zip(markers);
+        for (((name, ip), entries), marker) in entries {
+            let state =
+                RecordSetState::new(entries, marker, ip, &self.owner, None);
+            let change = if state.is_up_to_date {
+                RecordSetChange::None
+            } else if state.is_present {
+                RecordSetChange::Updated(state.published)
+            } else {
+                RecordSetChange::Created
+            };
+            if change != RecordSetChange::None {
+                // One record set we can't change stops the whole batch
+                if let Some(conflict) = &state.conflict {
+                    return Err(format!("\"{}\" {}", name, conflict).into());
+                }
+                record_sets
+                    .extend(self.ownership_changes(name, None, &state, ttl)?);
+                record_sets.push(address_record_set(name, ip, ttl, None));
+            }
+            changes.push(change);
+        }
+
+        if record_sets.is_empty() {
+            info!(zone = zone_id; "Every record set is already up to date.");
+            return Ok(changes);
+        }
+
+        let count = changes
+            .iter()
+            .filter(|change| **change != RecordSetChange::None)
+            .count();
+        let record_sets = record_sets
+            .into_iter()
+            .map(|record_set| change("UPSERT", record_set))
+            .collect();
+        change_record_sets(&client, zone_id, record_sets).await?;
+        for ((name, ip), change) in addresses.iter().zip(changes.iter()) {
+            if *change != RecordSetChange::None {
+                info!(
+                    record = name, zone = zone_id, ip = ip;
+                    "{} was updated.", name
+                );
+            }
+        }
+        info!(zone = zone_id; "Updated {} record set(s) in one change batch", count);
+
+        Ok(changes)
     }
 }
 
-async fn check_record_set(
+/// State of a record set as published on Route53
+struct RecordSetState {
+    is_present: bool,
+    is_up_to_date: bool,
+    published: Option<IpAddr>,
+    ownership: Ownership,
+    marker: Option<ResourceRecordSet>,
+
+    /// What would be lost by writing the record set, and the record sets
+    /// deleted to replace them
+    conflict: Option<String>,
+    conflicting: Vec<ResourceRecordSet>,
+}
+
+impl RecordSetState {
+    /// Finds the state of the record set among the ones with its name and
+    /// type, the one with its set identifier when it has a routing policy
+    fn new(
+        entries: Vec<ResourceRecordSet>,
+        marker: Option<ResourceRecordSet>,
+        ip: &IpAddr,
+        owner: &str,
+        routing: Option<&Routing<'_>>,
+    ) -> Self {
+        let set_identifier = routing.map(|routing| routing.set_identifier);
+        let own: Vec<&ResourceRecordSet> = entries
+            .iter()
+            .filter(|entry| {
+                routing.is_none()
+                    || entry.set_identifier.as_deref() == set_identifier
+            })
+            .collect();
+        let published: Vec<IpAddr> = own
+            .iter()
+            .flat_map(|entry| published_addresses(entry))
+            .collect();
+        let has_routing = own.iter().all(|entry| match routing {
+            Some(routing) => has_routing(entry, routing),
+            None => true,
+        });
+
+        RecordSetState {
+            is_present: !own.is_empty(),
+            is_up_to_date: published.contains(ip) && has_routing,
+            published: published
+                .iter()
+                .find(|&published_ip| published_ip == ip)
+                .or_else(|| published.first())
+                .copied(),
+            ownership: ownership(marker.as_ref(), owner, set_identifier),
+            marker,
+            conflict: conflict(&entries, routing),
+            conflicting: entries
+                .iter()
+                .filter(|entry| is_conflicting(entry, routing))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Returns the name of the routing policy of the entry
+fn policy(entry: &ResourceRecordSet) -> &'static str {
+    if entry.weight.is_some() {
+        "weighted"
+    } else if entry.failover.is_some() {
+        "failover"
+    } else if entry.multi_value_answer == Some(true) {
+        "multi-value answer"
+    } else if entry.region.is_some() {
+        "latency"
+    } else if entry.geo_location.is_some() {
+        "geolocation"
+    } else {
+        "routing policy"
+    }
+}
+
+/// Returns true if the entry has the routing settings of the record set
+fn has_routing(entry: &ResourceRecordSet, routing: &Routing<'_>) -> bool {
+    entry.weight == routing.weight
+        && entry.failover.as_deref()
+            == routing.failover.map(|failover| failover.as_str())
+        && entry.multi_value_answer.unwrap_or(false) == routing.multivalue
+        && entry.health_check_id.as_deref() == routing.health_check_id
+}
+
+/// Returns true if the entry can't stay along with the record set written
+/// by the tool, which has the routing policy if one is set
+fn is_conflicting(
+    entry: &ResourceRecordSet,
+    routing: Option<&Routing<'_>>,
+) -> bool {
+    let is_alias = entry.alias_target.is_some()
+        || entry.traffic_policy_instance_id.is_some();
+    match (routing, &entry.set_identifier) {
+        (None, set_identifier) => is_alias || set_identifier.is_some(),
+        // Routing policies can't be mixed with simple record sets or other
+        // policies
+        (Some(_), None) => true,
+        (Some(routing), Some(set_identifier)) => {
+            if set_identifier == routing.set_identifier {
+                is_alias
+            } else {
+                policy(entry) != routing.policy()
+            }
+        }
+    }
+}
+
+/// Describes what makes the record sets of a name and type clash with the
+/// record set written by the tool, with one value and the routing policy
+/// if one is set
+fn conflict(
+    entries: &[ResourceRecordSet],
+    routing: Option<&Routing<'_>>,
+) -> Option<String> {
+    let entry =
+        match entries.iter().find(|entry| is_conflicting(entry, routing)) {
+            Some(entry) => entry,
+            None => {
+                // Values that writing a single one would lose
+                let set_identifier =
+                    routing.map(|routing| routing.set_identifier);
+                let values = entries
+                    .iter()
+                    .filter(|entry| {
+                        entry.set_identifier.as_deref() == set_identifier
+                    })
+                    .map(|entry| {
+                        entry.resource_records.as_ref().map_or(0, Vec::len)
+                    })
+                    .max()?;
+                return if values > 1 {
+                    Some(format!("has {} values", values))
+                } else {
+                    None
+                };
+            }
+        };
+
+    if let Some(alias) = &entry.alias_target {
+        return Some(format!("is an alias to {}", alias.dns_name));
+    }
+    if let Some(instance_id) = &entry.traffic_policy_instance_id {
+        return Some(format!(
+            "is managed by the traffic policy instance {}",
+            instance_id
+        ));
+    }
+    match (routing, &entry.set_identifier) {
+        (None, Some(set_identifier)) => Some(format!(
+            "is a {} record set (set identifier \"{}\", {} in total), \
+             which would be lost",
+            policy(entry),
+            set_identifier,
+            entries.len()
+        )),
+        (Some(routing), Some(set_identifier)) => Some(format!(
+            "has {} record sets (set identifier \"{}\"), which can't be \
+             mixed with {} ones",
+            policy(entry),
+            set_identifier,
+            routing.policy()
+        )),
+        (Some(routing), None) => Some(format!(
+            "is a simple record set, which can't be mixed with {} ones",
+            routing.policy()
+        )),
+        (None, None) => None,
+    }
+}
+
+/// Returns the record type used for the address
+pub fn record_type(ip: &IpAddr) -> &'static str {
+    if ip.is_ipv4() {
+        "A"
+    } else {
+        "AAAA"
+    }
+}
+
+/// Returns the name of the TXT record set with the ownership marker of the
+/// record set. It has a name of its own so it doesn't get mixed with other
+/// TXT records, like SPF.
+pub fn owner_record_name(record_set: &str) -> String {
+    format!("{}{}", OWNER_RECORD_PREFIX, record_set)
+}
+
+/// Returns true if the entry is the ownership marker of the record set
+fn is_marker_of(entry: &ResourceRecordSet, record_set: &str) -> bool {
+    entry.type_ == "TXT"
+        && is_same_name(&entry.name, &owner_record_name(record_set))
+}
+
+/// Returns the owner and set identifier of an ownership marker value, if
+/// it is one
+fn parse_marker(value: &str) -> Option<(&str, Option<&str>)> {
+    let fields: Vec<&str> = value.trim_matches('"').split(',').collect();
+    if !fields.contains(&"managed-by=rust-aws-ddns") {
+        return None;
+    }
+    let owner = fields
+        .iter()
+        .find_map(|field| field.strip_prefix("owner="))?;
+    let set_identifier =
+        fields.iter().find_map(|field| field.strip_prefix("set="));
+    Some((owner, set_identifier))
+}
+
+/// Returns the ownership marker record set, external-dns style. Record sets
+/// sharing the name with a routing policy have a value each, so the ones of
+/// the other set identifiers are kept.
+fn marker_record_set(
+    record_set: &str,
+    current: Option<&ResourceRecordSet>,
+    owner: &str,
+    set_identifier: Option<&str>,
+    ttl: i64,
+) -> ResourceRecordSet {
+    use rusoto_route53::ResourceRecord;
+
+    let mut records: Vec<ResourceRecord> = current
+        .and_then(|current| current.resource_records.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|record| match parse_marker(&record.value) {
+            Some((_, marker_set)) => marker_set != set_identifier,
+            None => false,
+        })
+        .collect();
+    let value = match set_identifier {
+        Some(set_identifier) => format!(
+            "\"managed-by=rust-aws-ddns,owner={},set={}\"",
+            owner, set_identifier
+        ),
+        None => format!("\"managed-by=rust-aws-ddns,owner={}\"", owner),
+    };
+    records.push(ResourceRecord { value });
+
+    ResourceRecordSet {
+        name: owner_record_name(record_set),
+        type_: String::from("TXT"),
+        ttl: Some(ttl),
+        resource_records: Some(records),
+        ..Default::default()
+    }
+}
+
+/// Returns who manages the record set with the set identifier, according
+/// to its marker
+fn ownership(
+    marker: Option<&ResourceRecordSet>,
+    owner: &str,
+    set_identifier: Option<&str>,
+) -> Ownership {
+    let records = marker
+        .and_then(|marker| marker.resource_records.as_ref())
+        .into_iter()
+        .flatten();
+
+    let mut foreign = None;
+    for record in records {
+        match parse_marker(&record.value) {
+            Some((marker_owner, marker_set))
+                if marker_set == set_identifier =>
+            {
+                if marker_owner == owner {
+                    return Ownership::Owned;
+                }
+                foreign = Some(marker_owner.to_string());
+            }
+            _ => {}
+        }
+    }
+    foreign.map_or(Ownership::Unmarked, Ownership::Foreign)
+}
+
+/// Returns true if both names refer to the same record set, regardless of the
+/// trailing dot and case
+pub fn is_same_name(name: &str, other: &str) -> bool {
+    name.trim_end_matches('.')
+        .eq_ignore_ascii_case(other.trim_end_matches('.'))
+}
+
+/// Finds the hosted zone by its id (with or without the `/hostedzone/`
+/// prefix) or name
+pub fn find_hosted_zone<'a>(
+    hosted_zones: &'a [(String, String)],
+    zone: &str,
+) -> Option<&'a (String, String)> {
+    hosted_zones.iter().find(|(zone_id, zone_name)| {
+        zone_id == zone
+            || zone_id.trim_start_matches("/hostedzone/") == zone
+            || is_same_name(zone_name, zone)
+    })
+}
+
+/// Fetches the record sets of the zone, following the pagination, until
+/// `visit` returns false
+async fn fetch_record_sets<F>(
     client: &AwsRoute53Client,
     zone_id: &str,
-    record_set: &str,
-    ip: &IpAddr,
-) -> (bool, bool) {
+    mut visit: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(&ResourceRecordSet) -> bool,
+{
     use rusoto_route53::{
         ListResourceRecordSetsRequest, ListResourceRecordSetsResponse,
     };
 
-    let mut is_recordset_present = false;
-    let mut is_recordset_uptodate = false;
-
     // Fetching the recordSets for the specified zone_id
     let mut prev_response: Option<ListResourceRecordSetsResponse> = None;
-    'outer: loop {
+    loop {
         let mut request = ListResourceRecordSetsRequest {
             hosted_zone_id: zone_id.to_string(),
             max_items: None,
@@ -115,87 +814,352 @@ async fn check_record_set(
             request.start_record_type = ref_response.next_record_type;
         }
 
-        let response = client.list_resource_record_sets(request).await.unwrap();
+        let response = client.list_resource_record_sets(request).await;
+        crate::metrics::route53_call(
+            "ListResourceRecordSets",
+            response.is_ok(),
+        );
+        let response = response?;
 
-        // Looking for the desired record_set
         for record_set_entry in response.resource_record_sets.iter() {
-            if record_set_entry.name == record_set {
-                let wanted_record_type =
-                    if ip.is_ipv4() { "A" } else { "AAAA" };
-
-                if record_set_entry.type_ == wanted_record_type {
-                    is_recordset_present = true;
-
-                    if let Some(records) = &record_set_entry.resource_records {
-                        for record in records.iter() {
-                            let record_ip: Result<IpAddr, _> =
-                                record.value.parse();
-
-                            if record_ip.is_ok() {
-                                let record_ip = record_ip.unwrap();
-                                if record_ip == *ip {
-                                    is_recordset_uptodate = true;
-                                    break 'outer;
-                                }
-                            }
-                        }
-                    }
-
-                    break 'outer;
-                }
+            if !visit(record_set_entry) {
+                return Ok(());
             }
         }
 
         // Fetching next recordSets, if necessary
         if !response.is_truncated {
-            break;
+            return Ok(());
         }
         prev_response = Some(response);
     }
-
-    (is_recordset_present, is_recordset_uptodate)
 }
 
-async fn update_record_set(
+/// Looks for the record set with the specified name and type, and set
+/// identifier if one is given
+async fn find_record_set(
     client: &AwsRoute53Client,
     zone_id: &str,
     record_set: &str,
+    record_type: &str,
+    set_identifier: Option<&str>,
+) -> Result<Option<ResourceRecordSet>, Box<dyn std::error::Error>> {
+    let mut found = None;
+    fetch_record_sets(client, zone_id, |entry| {
+        if is_same_name(&entry.name, record_set)
+            && entry.type_ == record_type
+            && set_identifier.iter().all(|set_identifier| {
+                entry.set_identifier.as_deref() == Some(set_identifier)
+            })
+        {
+            found = Some(entry.clone());
+            return false;
+        }
+        true
+    })
+    .await?;
+
+    Ok(found)
+}
+
+/// Returns the addresses published on the record set
+pub fn published_addresses(record_set: &ResourceRecordSet) -> Vec<IpAddr> {
+    match &record_set.resource_records {
+        Some(records) => records
+            .iter()
+            .filter_map(|record| record.value.parse().ok())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Reads the record sets of the address family along with the ownership
+/// marker
+async fn check_record_set(
+    client: &AwsRoute53Client,
+    target: &RecordSet<'_>,
     ip: &IpAddr,
-) {
-    use rusoto_route53::{
-        Change, ChangeBatch, ChangeResourceRecordSetsRequest, ResourceRecord,
-        ResourceRecordSet,
-    };
+    owner: &str,
+) -> Result<RecordSetState, Box<dyn std::error::Error>> {
+    let (zone_id, record_set) = (target.zone_id, target.name);
+    let (mut entries, mut marker) = (Vec::new(), None);
+    fetch_record_sets(client, zone_id, |found| {
+        if is_same_name(&found.name, record_set)
+            && found.type_ == record_type(ip)
+        {
+            entries.push(found.clone());
+        } else if is_marker_of(found, record_set) {
+            marker = Some(found.clone());
+        }
+        true
+    })
+    .await?;
 
-    let new_record_set = ResourceRecordSet {
+    Ok(RecordSetState::new(
+        entries,
+        marker,
+        ip,
+        owner,
+        target.routing.as_ref(),
+    ))
+}
+
+/// Returns the record set pointing the name to the address, with the
+/// routing policy if one is set
+fn address_record_set(
+    record_set: &str,
+    ip: &IpAddr,
+    ttl: i64,
+    routing: Option<&Routing<'_>>,
+) -> ResourceRecordSet {
+    use rusoto_route53::ResourceRecord;
+
+    ResourceRecordSet {
         alias_target: None,
-        failover: None,
+        failover: routing
+            .and_then(|routing| routing.failover)
+            .map(|failover| failover.as_str().to_string()),
         geo_location: None,
-        health_check_id: None,
-        multi_value_answer: None,
+        health_check_id: routing
+            .and_then(|routing| routing.health_check_id)
+            .map(String::from),
+        multi_value_answer: routing
+            .filter(|routing| routing.multivalue)
+            .map(|_| true),
         name: record_set.to_string(),
         region: None,
-        set_identifier: None,
-        ttl: Some(120),
+        set_identifier: routing
+            .map(|routing| routing.set_identifier.to_string()),
+        ttl: Some(ttl),
         traffic_policy_instance_id: None,
-        type_: String::from(if ip.is_ipv4() { "A" } else { "AAAA" }),
-        weight: None,
+        type_: String::from(record_type(ip)),
+        weight: routing.and_then(|routing| routing.weight),
 
         resource_records: Some(vec![ResourceRecord {
             value: ip.to_string(),
         }]),
-    };
+    }
+}
+
+fn change(action: &str, record_set: ResourceRecordSet) -> Change {
+    Change {
+        action: action.to_string(),
+        resource_record_set: record_set,
+    }
+}
+
+/// Sends the changes on a single change batch, so they are applied all
+/// together or not at all
+async fn change_record_sets(
+    client: &AwsRoute53Client,
+    zone_id: &str,
+    changes: Vec<Change>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use rusoto_route53::{ChangeBatch, ChangeResourceRecordSetsRequest};
 
     let request = ChangeResourceRecordSetsRequest {
         hosted_zone_id: zone_id.to_string(),
         change_batch: ChangeBatch {
             comment: Some("changed by rust-aws-ddns".to_string()),
-            changes: vec![Change {
-                action: "UPSERT".to_string(),
-                resource_record_set: new_record_set,
-            }],
+            changes,
         },
     };
 
-    client.change_resource_record_sets(request).await.unwrap();
+    let result = client.change_resource_record_sets(request).await;
+    crate::metrics::route53_call("ChangeResourceRecordSets", result.is_ok());
+    result?;
+
+    Ok(())
+}
+
+/// Record sets used by the tests of the modules reading Route53
+#[cfg(test)]
+pub mod fixtures {
+    use rusoto_route53::{ResourceRecord, ResourceRecordSet};
+
+    pub fn records(values: &[&str]) -> Option<Vec<ResourceRecord>> {
+        Some(
+            values
+                .iter()
+                .map(|value| ResourceRecord {
+                    value: value.to_string(),
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns a record set of home.example.com.
+    pub fn entry(
+        record_type: &str,
+        values: &[&str],
+        ttl: i64,
+    ) -> ResourceRecordSet {
+        ResourceRecordSet {
+            name: String::from("home.example.com."),
+            type_: String::from(record_type),
+            ttl: Some(ttl),
+            resource_records: records(values),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod record_set_tests {
+    use super::*;
+    use crate::config::FailoverRole;
+    use crate::route53_client::fixtures::{entry, records};
+
+    fn weighted(set_identifier: &str, ip: &str) -> ResourceRecordSet {
+        let mut weighted = entry("A", &[ip], 60);
+        weighted.set_identifier = Some(String::from(set_identifier));
+        weighted.weight = Some(10);
+        weighted
+    }
+
+    #[test]
+    fn conflicts() {
+        assert_eq!(conflict(&[], None), None);
+        assert_eq!(conflict(&[entry("A", &["192.0.2.1"], 60)], None), None);
+        assert_eq!(
+            conflict(&[entry("A", &["192.0.2.1", "192.0.2.2"], 60)], None)
+                .unwrap(),
+            "has 2 values"
+        );
+
+        let mut alias = entry("A", &[], 60);
+        alias.resource_records = None;
+        alias.alias_target = Some(rusoto_route53::AliasTarget {
+            dns_name: String::from("lb.example.net."),
+            ..Default::default()
+        });
+        assert_eq!(
+            conflict(&[alias], None).unwrap(),
+            "is an alias to lb.example.net."
+        );
+
+        let entries = [
+            weighted("home", "192.0.2.1"),
+            weighted("office", "198.51.100.1"),
+        ];
+        assert_eq!(
+            conflict(&entries, None).unwrap(),
+            "is a weighted record set (set identifier \"home\", 2 in total), \
+             which would be lost"
+        );
+    }
+
+    #[test]
+    fn routing_policies() {
+        let routing = Routing {
+            set_identifier: "home",
+            weight: Some(10),
+            failover: None,
+            multivalue: false,
+            health_check_id: None,
+        };
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        // Only the record set with the set identifier is ours
+        let entries = vec![
+            weighted("office", "198.51.100.1"),
+            weighted("home", "192.0.2.1"),
+        ];
+        assert_eq!(conflict(&entries, Some(&routing)), None);
+        let state =
+            RecordSetState::new(entries, None, &ip, "nas", Some(&routing));
+        assert!(state.is_present && state.is_up_to_date);
+
+        // A new weight has to be written
+        let mut other_weight = routing;
+        other_weight.weight = Some(20);
+        let state = RecordSetState::new(
+            vec![weighted("home", "192.0.2.1")],
+            None,
+            &ip,
+            "nas",
+            Some(&other_weight),
+        );
+        assert!(state.is_present && !state.is_up_to_date);
+
+        // Other policies and simple record sets can't be mixed
+        let mut failover = weighted("office", "198.51.100.1");
+        failover.weight = None;
+        failover.failover = Some(String::from("PRIMARY"));
+        let entries = vec![failover, entry("A", &["203.0.113.1"], 60)];
+        assert_eq!(
+            conflict(&entries, Some(&routing)).unwrap(),
+            "has failover record sets (set identifier \"office\"), which \
+             can't be mixed with weighted ones"
+        );
+        let state =
+            RecordSetState::new(entries, None, &ip, "nas", Some(&routing));
+        assert!(!state.is_present);
+        assert_eq!(state.conflicting.len(), 2);
+
+        let secondary = Routing {
+            set_identifier: "backup",
+            weight: None,
+            failover: Some(FailoverRole::Secondary),
+            multivalue: false,
+            health_check_id: Some("abcd-1234"),
+        };
+        let written =
+            address_record_set("home.example.com.", &ip, 60, Some(&secondary));
+        assert_eq!(written.set_identifier.as_deref(), Some("backup"));
+        assert_eq!(written.failover.as_deref(), Some("SECONDARY"));
+        assert_eq!(written.health_check_id.as_deref(), Some("abcd-1234"));
+        assert_eq!(written.multi_value_answer, None);
+        assert!(has_routing(&written, &secondary));
+    }
+
+    #[test]
+    fn markers() {
+        let written =
+            marker_record_set("home.example.com.", None, "nas", None, 300);
+        assert_eq!(written.name, "_rust-aws-ddns.home.example.com.");
+        assert!(is_marker_of(&written, "Home.example.com"));
+        assert_eq!(ownership(Some(&written), "nas", None), Ownership::Owned);
+        assert_eq!(
+            ownership(Some(&written), "router", None),
+            Ownership::Foreign(String::from("nas"))
+        );
+
+        assert_eq!(ownership(None, "nas", None), Ownership::Unmarked);
+        let mut other = written.clone();
+        other.resource_records = records(&["\"v=spf1 -all\"", "\"owner=nas\""]);
+        assert_eq!(ownership(Some(&other), "nas", None), Ownership::Unmarked);
+
+        // Each set identifier has a marker of its own
+        let home = marker_record_set(
+            "home.example.com.",
+            Some(&written),
+            "nas",
+            Some("home"),
+            300,
+        );
+        let office = marker_record_set(
+            "home.example.com.",
+            Some(&home),
+            "router",
+            Some("office"),
+            300,
+        );
+        assert_eq!(
+            office.resource_records,
+            records(&[
+                "\"managed-by=rust-aws-ddns,owner=nas\"",
+                "\"managed-by=rust-aws-ddns,owner=nas,set=home\"",
+                "\"managed-by=rust-aws-ddns,owner=router,set=office\"",
+            ])
+        );
+        assert_eq!(
+            ownership(Some(&office), "nas", Some("home")),
+            Ownership::Owned
+        );
+        assert_eq!(
+            ownership(Some(&office), "nas", Some("office")),
+            Ownership::Foreign(String::from("router"))
+        );
+    }
 }
@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{AdaptiveTtlConfig, RecordSet, StabilizationConfig};
+
+const STATE_FILE_NAME: &str = "state.json";
+
+/// Address seen on the last checks that isn't published yet
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PendingAddress {
+    pub ip: IpAddr,
+
+    /// Unix time of the first check that saw it
+    pub since: i64,
+
+    /// Number of consecutive checks that saw it
+    pub checks: u32,
+}
+
+/// Record set as last written by the tool
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PublishedRecord {
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub values: Vec<String>,
+    pub ttl: i64,
+}
+
+/// What is remembered about a record set between runs
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct RecordState {
+    /// Last address known to be published
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stable_ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending: Option<PendingAddress>,
+
+    /// TTL set by the adaptive TTL, and the Unix time it was set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl_since: Option<i64>,
+
+    /// Last record set written, to tell changes made by others
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub written: Option<PublishedRecord>,
+
+    /// Last drift reported, so it is only reported once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drift: Option<String>,
+
+    /// Consecutive failed updates, for the failure notifications
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failures: Option<u32>,
+}
+
+/// Checks (and seconds) left before a pending address is published. Either
+/// of them reaching zero is enough.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Countdown {
+    pub ip: IpAddr,
+    pub checks_left: Option<u32>,
+    pub seconds_left: Option<i64>,
+}
+
+impl std::fmt::Display for Countdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut left = Vec::new();
+        if let Some(checks) = self.checks_left {
+            left.push(format!("{} check(s)", checks));
+        }
+        if let Some(seconds) = self.seconds_left {
+            left.push(format!("{}s", seconds));
+        }
+        write!(f, "{} pending, published in {}", self.ip, left.join(" or "))
+    }
+}
+
+impl RecordState {
+    /// Returns the countdown of the pending address at the time
+    pub fn countdown(
+        &self,
+        stabilization: &StabilizationConfig,
+        now: i64,
+    ) -> Option<Countdown> {
+        let pending = self.pending.as_ref()?;
+        Some(Countdown {
+            ip: pending.ip,
+            checks_left: stabilization
+                .checks
+                .map(|checks| checks.saturating_sub(pending.checks)),
+            seconds_left: stabilization
+                .seconds
+                .map(|seconds| (pending.since + seconds as i64 - now).max(0)),
+        })
+    }
+
+    /// Records the address detected on a check, returning the countdown
+    /// while it has to wait before being published
+    pub fn observe(
+        &mut self,
+        ip: IpAddr,
+        stabilization: &StabilizationConfig,
+        now: i64,
+    ) -> Option<Countdown> {
+        if self.stable_ip == Some(ip) {
+            self.pending = None;
+            return None;
+        }
+
+        match &mut self.pending {
+            Some(pending) if pending.ip == ip => pending.checks += 1,
+            _ => {
+                self.pending = Some(PendingAddress {
+                    ip,
+                    since: now,
+                    checks: 1,
+                })
+            }
+        }
+
+        let countdown = self.countdown(stabilization, now)?;
+        let is_stable = countdown.checks_left == Some(0)
+            || countdown.seconds_left == Some(0);
+        if is_stable {
+            None
+        } else {
+            Some(countdown)
+        }
+    }
+
+    /// Counts one more failure on the current streak, returning its length
+    pub fn failed(&mut self) -> u32 {
+        let failures = self.failures.unwrap_or(0) + 1;
+        self.failures = Some(failures);
+        failures
+    }
+
+    /// Ends the failure streak after a successful check or update
+    pub fn succeeded(&mut self) {
+        self.failures = None;
+    }
+
+    /// Records that the address is published
+    pub fn published(&mut self, ip: IpAddr) {
+        self.stable_ip = Some(ip);
+        self.pending = None;
+    }
+
+    /// Records the record set written to Route53
+    pub fn wrote(&mut self, ip: IpAddr, ttl: i64) {
+        self.written = Some(PublishedRecord {
+            record_type: String::from(if ip.is_ipv4() { "A" } else { "AAAA" }),
+            values: vec![ip.to_string()],
+            ttl,
+        });
+    }
+
+    /// Drops the TTL to the minimum after an address change, returning it
+    pub fn reset_ttl(
+        &mut self,
+        adaptive_ttl: &AdaptiveTtlConfig,
+        now: i64,
+    ) -> i64 {
+        self.ttl = Some(adaptive_ttl.min);
+        self.ttl_since = Some(now);
+        adaptive_ttl.min
+    }
+
+    /// Returns the new TTL when it has to change: a step up after
+    /// `step_seconds` without changes, or when it is out of the configured
+    /// range. Without a TTL on the state, `current` is taken as the start.
+    pub fn ttl_step(
+        &mut self,
+        adaptive_ttl: &AdaptiveTtlConfig,
+        current: i64,
+        now: i64,
+    ) -> Option<i64> {
+        let ttl = self.ttl.unwrap_or(current);
+        let since = *self.ttl_since.get_or_insert(now);
+
+        let next = if ttl < adaptive_ttl.min || ttl > adaptive_ttl.max {
+            ttl.max(adaptive_ttl.min).min(adaptive_ttl.max)
+        } else if ttl < adaptive_ttl.max
+            && now - since >= adaptive_ttl.step_seconds()
+        {
+            (ttl * 2).min(adaptive_ttl.max)
+        } else {
+            self.ttl = Some(ttl);
+            return None;
+        };
+
+        self.ttl = Some(next);
+        self.ttl_since = Some(now);
+        Some(next)
+    }
+}
+
+/// State kept between runs, on `state_file`
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    #[serde(default)]
+    pub records: BTreeMap<String, RecordState>,
+}
+
+/// Returns the key of the record set on the state file
+fn record_key(record_set: &RecordSet<'_>) -> String {
+    let key = format!(
+        "{} {} {}",
+        record_set.zone_id,
+        record_set.name,
+        if record_set.is_v6 { "AAAA" } else { "A" }
+    );
+    match &record_set.routing {
+        Some(routing) => format!("{} {}", key, routing.set_identifier),
+        None => key,
+    }
+}
+
+impl State {
+    /// Loads the state, starting over when the file is missing or can't be
+    /// read
+    pub fn load(path: &str) -> Self {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return State::default(),
+        };
+
+        match serde_json::from_str(&contents) {
+            Ok(state) => state,
+            Err(err) => {
+                warn!("Ignoring the state file {}: {}", path, err);
+                State::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(dir) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let contents = serde_json::to_string_pretty(self)?;
+        crate::config::write_private(path, &contents)
+    }
+
+    pub fn record(&mut self, record_set: &RecordSet<'_>) -> &mut RecordState {
+        self.records.entry(record_key(record_set)).or_default()
+    }
+
+    pub fn get(&self, record_set: &RecordSet<'_>) -> Option<&RecordState> {
+        self.records.get(&record_key(record_set))
+    }
+}
+
+/// Returns the default state file: on the directory systemd creates for the
+/// service (`StateDirectory=`), on `/var/lib/rust-aws-ddns` for root and on
+/// the XDG state directory otherwise
+pub fn default_file() -> String {
+    let dir = if let Some(dir) = std::env::var_os("STATE_DIRECTORY") {
+        PathBuf::from(dir)
+    } else if unsafe { libc::geteuid() } == 0 {
+        PathBuf::from("/var/lib/rust-aws-ddns")
+    } else {
+        std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME")
+                    .map(|home| PathBuf::from(home).join(".local/state"))
+            })
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("rust-aws-ddns")
+    };
+
+    dir.join(STATE_FILE_NAME).to_string_lossy().into_owned()
+}
+
+/// Returns the current Unix time
+pub fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::*;
+
+    #[test]
+    fn stabilization() {
+        let stabilization = StabilizationConfig {
+            checks: Some(3),
+            seconds: Some(600),
+        };
+        let old: IpAddr = "203.0.113.1".parse().unwrap();
+        let new: IpAddr = "203.0.113.2".parse().unwrap();
+
+        let mut state = RecordState::default();
+        state.published(old);
+        assert_eq!(state.observe(old, &stabilization, 0), None);
+
+        let countdown = state.observe(new, &stabilization, 100).unwrap();
+        assert_eq!(countdown.checks_left, Some(2));
+        assert_eq!(countdown.seconds_left, Some(600));
+        assert_eq!(
+            countdown.to_string(),
+            "203.0.113.2 pending, published in 2 check(s) or 600s"
+        );
+
+        // Bouncing back to the published address cancels it
+        assert_eq!(state.observe(old, &stabilization, 160), None);
+        assert!(state.pending.is_none());
+
+        assert!(state.observe(new, &stabilization, 200).is_some());
+        assert!(state.observe(new, &stabilization, 260).is_some());
+        assert_eq!(state.observe(new, &stabilization, 320), None);
+
+        // The time is enough as well
+        let mut state = RecordState::default();
+        assert!(state.observe(new, &stabilization, 0).is_some());
+        assert_eq!(state.observe(new, &stabilization, 600), None);
+    }
+
+    #[test]
+    fn adaptive_ttl() {
+        let adaptive_ttl = AdaptiveTtlConfig {
+            min: 60,
+            max: 300,
+            step_seconds: Some(1000),
+        };
+
+        // Starting from the static TTL
+        let mut state = RecordState::default();
+        assert_eq!(state.ttl_step(&adaptive_ttl, 120, 0), None);
+        assert_eq!(state.ttl_step(&adaptive_ttl, 120, 999), None);
+        assert_eq!(state.ttl_step(&adaptive_ttl, 120, 1000), Some(240));
+        assert_eq!(state.ttl_step(&adaptive_ttl, 120, 2000), Some(300));
+        assert_eq!(state.ttl_step(&adaptive_ttl, 120, 5000), None);
+
+        state.reset_ttl(&adaptive_ttl, 6000);
+        assert_eq!(state.ttl, Some(60));
+        assert_eq!(state.ttl_step(&adaptive_ttl, 120, 6500), None);
+        assert_eq!(state.ttl_step(&adaptive_ttl, 120, 7000), Some(120));
+
+        // Out of range after a configuration change
+        let mut state = RecordState::default();
+        assert_eq!(state.ttl_step(&adaptive_ttl, 3600, 0), Some(300));
+    }
+
+    #[test]
+    fn failure_streak() {
+        let mut state = RecordState::default();
+        assert_eq!(state.failed(), 1);
+        assert_eq!(state.failed(), 2);
+
+        // Kept between runs
+        let json = serde_json::to_string(&state).unwrap();
+        let mut state: RecordState = serde_json::from_str(&json).unwrap();
+        assert_eq!(state.failed(), 3);
+
+        // A success starts a new streak
+        state.succeeded();
+        assert_eq!(state.failed(), 1);
+    }
+}
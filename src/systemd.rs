@@ -0,0 +1,246 @@
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Name used on the generated unit files
+const UNIT_NAME: &str = "rust-aws-ddns";
+
+/// Sends a state notification to systemd (`READY=1`, `STATUS=...`).
+/// Nothing is done when we weren't started by systemd with `NOTIFY_SOCKET`.
+pub fn notify(state: &str) {
+    let socket_path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    if let Err(err) = send(&socket_path, state) {
+        warn!("Failed to notify systemd: {}", err);
+    }
+}
+
+fn send(socket_path: &str, state: &str) -> std::io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    // Paths starting with @ are on the abstract namespace
+    if let Some(name) = socket_path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+
+        let addr = SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+    } else {
+        socket.send_to(state.as_bytes(), socket_path)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the interval to feed the watchdog, if it is enabled for us
+fn watchdog_interval() -> Option<Duration> {
+    // WATCHDOG_PID, when present, must be our own pid
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Feeds the watchdog, if it is enabled for us. Called between record sets
+/// and while waiting, so that only a hung step gets the service restarted.
+pub fn feed_watchdog() {
+    if watchdog_interval().is_some() {
+        notify("WATCHDOG=1");
+    }
+}
+
+/// Watchdog timeout of the daemon. The watchdog is fed after each record
+/// set and while waiting, so it only has to cover one update step: the
+/// provider requests (`ip_address::PROVIDER_TIMEOUT` each), the Route53
+/// calls and the hooks (30 seconds by default).
+const WATCHDOG_SEC: u64 = 300;
+
+/// Sleeps for the specified duration, feeding the watchdog meanwhile.
+/// The watchdog is only fed from the update loop, so a hung update gets the
+/// service restarted.
+pub async fn sleep(duration: Duration) {
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return tokio::time::delay_for(duration).await,
+    };
+
+    let deadline = Instant::now() + duration;
+    loop {
+        notify("WATCHDOG=1");
+
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        tokio::time::delay_for(interval.min(deadline - now)).await;
+    }
+}
+
+/// Sandboxing of the service. It would keep hook commands from changing
+/// anything on the system, so it is left out when hooks are configured.
+const HARDENING: &str = "\n\
+    NoNewPrivileges=yes\n\
+    ProtectSystem=strict\n\
+    ProtectHome=read-only\n\
+    PrivateTmp=yes\n\
+    PrivateDevices=yes\n\
+    ProtectKernelTunables=yes\n\
+    ProtectKernelModules=yes\n\
+    ProtectControlGroups=yes\n\
+    RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK\n\
+    RestrictNamespaces=yes\n\
+    RestrictRealtime=yes\n\
+    LockPersonality=yes\n\
+    MemoryDenyWriteExecute=yes\n\
+    SystemCallArchitectures=native\n\
+    CapabilityBoundingSet=\n";
+
+fn service_unit(
+    exec_start: &str,
+    working_dir: &Path,
+    daemon: bool,
+    hardened: bool,
+) -> String {
+    let service = if daemon {
+        format!(
+            "Type=notify\n\
+             WatchdogSec={}\n\
+             Restart=on-failure\n\
+             RestartSec=30\n",
+            WATCHDOG_SEC
+        )
+    } else {
+        String::from("Type=oneshot\n")
+    };
+
+    format!(
+        "[Unit]\n\
+         Description=Route53 DDNS updater\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         {}\
+         ExecStart={}\n\
+         WorkingDirectory={}\n\
+         StateDirectory={}\n\
+         {}\
+         {}",
+        service,
+        exec_start,
+        working_dir.display(),
+        UNIT_NAME,
+        if hardened { HARDENING } else { "" },
+        if daemon {
+            "\n[Install]\nWantedBy=multi-user.target\n"
+        } else {
+            ""
+        }
+    )
+}
+
+fn timer_unit(interval: u64) -> String {
+    format!(
+        "[Unit]\n\
+         Description=Runs the Route53 DDNS updater periodically\n\
+         \n\
+         [Timer]\n\
+         OnBootSec=1min\n\
+         OnUnitActiveSec={}s\n\
+         RandomizedDelaySec=30s\n\
+         \n\
+         [Install]\n\
+         WantedBy=timers.target\n",
+        interval
+    )
+}
+
+fn absolute_path(path: &str) -> std::io::Result<PathBuf> {
+    match std::fs::canonicalize(path) {
+        Ok(path) => Ok(path),
+        Err(_) => Ok(std::env::current_dir()?.join(path)),
+    }
+}
+
+/// Writes the service (and timer) unit files into the specified directory.
+/// With `daemon` the service keeps running instead of being triggered by
+/// the timer. Without `hardened` the service isn't sandboxed, for hooks.
+pub fn install_units(
+    unit_dir: &str,
+    config_file: &str,
+    credentials_file: Option<&str>,
+    daemon: bool,
+    interval: u64,
+    hardened: bool,
+) -> std::io::Result<Vec<PathBuf>> {
+    let exe = std::env::current_exe()?;
+    let config_file = absolute_path(config_file)?;
+    let working_dir = config_file
+        .parent()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/"));
+
+    let mut exec_start =
+        format!("{} -c {}", exe.display(), config_file.display());
+    if let Some(credentials_file) = credentials_file {
+        let credentials_file = absolute_path(credentials_file)?;
+        exec_start.push_str(&format!(" --csv {}", credentials_file.display()));
+    }
+    exec_start.push_str(" --log journald");
+    if daemon {
+        exec_start.push_str(&format!(" --daemon --interval {}", interval));
+    }
+
+    let unit_dir = Path::new(unit_dir);
+    let service_path = unit_dir.join(format!("{}.service", UNIT_NAME));
+    std::fs::write(
+        &service_path,
+        service_unit(&exec_start, &working_dir, daemon, hardened),
+    )?;
+
+    let mut written = vec![service_path];
+    if !daemon {
+        let timer_path = unit_dir.join(format!("{}.timer", UNIT_NAME));
+        std::fs::write(&timer_path, timer_unit(interval))?;
+        written.push(timer_path);
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod systemd_tests {
+    use super::*;
+
+    #[test]
+    fn watchdog() {
+        let unit = service_unit("rust-aws-ddns", Path::new("/etc"), true, true);
+        assert!(unit.contains("WatchdogSec=300\n"));
+
+        let unit =
+            service_unit("rust-aws-ddns", Path::new("/etc"), false, true);
+        assert!(!unit.contains("WatchdogSec"));
+    }
+
+    #[test]
+    fn hardening() {
+        let unit = service_unit("rust-aws-ddns", Path::new("/etc"), true, true);
+        assert!(
+            unit.contains("StateDirectory=rust-aws-ddns\n\nNoNewPrivileges")
+        );
+        assert!(unit.contains("CapabilityBoundingSet=\n\n[Install]"));
+
+        // Hooks need to change the system
+        let unit =
+            service_unit("rust-aws-ddns", Path::new("/etc"), true, false);
+        assert!(!unit.contains("ProtectSystem"));
+        assert!(unit.contains("StateDirectory=rust-aws-ddns\n\n[Install]"));
+    }
+}